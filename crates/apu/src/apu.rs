@@ -3,12 +3,14 @@ use ringbuf::{Consumer, Producer, SharedRb};
 use sdl2::audio::{AudioCallback, AudioDevice};
 use std::{mem::MaybeUninit, sync::Arc};
 
+use cartridge::Region;
+
 use crate::constants::*;
 use crate::dmc::Dmc;
 use crate::noise::Noise;
 use crate::pulse_wave::PulseWave;
 use crate::triangle_wave::TriangleWave;
-use crate::wave_trait::WaveTrait;
+use crate::wave_trait::{AsWave, WaveTrait};
 
 pub struct Apu {
     triangle: TriangleWave,
@@ -17,9 +19,15 @@ pub struct Apu {
     noise: Noise,
     dmc: Dmc,
     tick: usize,
+    region: Region,
+    frame_steps: [usize; 5],
+    sample_tick: usize,
+    sample_timing: Vec<bool>,
     ringbuf_prod: Producer<f32, Arc<SharedRb<f32, Vec<MaybeUninit<f32>>>>>,
+    // 0x4017
     mode: bool,
-    _inhib_intr: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
     //
     pub audio_device: Option<AudioDevice<ApuSDL>>,
 }
@@ -36,13 +44,55 @@ impl Apu {
             noise: Noise::new(),
             dmc: Dmc::new(),
             tick: 0,
+            region: Region::NTSC,
+            frame_steps: frame_sequencer_steps(Region::NTSC),
+            sample_tick: 0,
+            sample_timing: sample_timing(Region::NTSC),
             ringbuf_prod: prod,
             mode: false,
-            _inhib_intr: true,
+            irq_inhibit: false,
+            frame_irq: false,
             audio_device,
         }
     }
 
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.frame_steps = frame_sequencer_steps(region);
+        self.sample_timing = sample_timing(region);
+        self.tick = 0;
+        self.sample_tick = 0;
+        self.pulse1.as_mut_wave().cpu_freq = region.cpu_freq();
+        self.pulse2.as_mut_wave().cpu_freq = region.cpu_freq();
+        self.triangle.as_mut_wave().cpu_freq = region.cpu_freq();
+        self.noise.as_mut_wave().cpu_freq = region.cpu_freq();
+        self.noise.set_region(region);
+        self.dmc.set_region(region);
+    }
+
+    // $4015 is written with 0 and the frame counter keeps its mode
+    // https://www.nesdev.org/wiki/CPU_power_up_state
+    pub fn reset(&mut self) {
+        self.write_register(0x4015, 0);
+        self.frame_irq = false;
+        self.tick = 0;
+        self.triangle.reset_phase();
+        self.dmc.reset();
+    }
+
+    // All registers are zero, as if $4017 was written with 0
+    pub fn power_cycle(&mut self) {
+        self.triangle = TriangleWave::new();
+        self.pulse1 = PulseWave::new(1);
+        self.pulse2 = PulseWave::new(2);
+        self.noise = Noise::new();
+        self.dmc = Dmc::new();
+        self.mode = false;
+        self.irq_inhibit = false;
+        self.frame_irq = false;
+        self.set_region(self.region);
+    }
+
     pub fn write_register(&mut self, address: u16, data: u8) {
         match address {
             // pulse 1
@@ -76,10 +126,73 @@ impl Apu {
                 self.noise.set_enable(data & 0b1000 > 0);
                 self.dmc.set_enable(data & 0b1_0000 > 0);
             }
+            // frame counter
+            0x4017 => {
+                self.mode = data & 0b1000_0000 > 0;
+                self.irq_inhibit = data & 0b0100_0000 > 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.tick = 0;
+                // The 5-step mode clocks all units right away
+                if self.mode {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
             _ => {}
         };
     }
 
+    // $4015. Reading acknowledges the frame IRQ but not the DMC IRQ
+    pub fn read_status(&mut self, trace: bool) -> u8 {
+        let data = u8::from(self.pulse1.as_wave().length_counter > 0)
+            | u8::from(self.pulse2.as_wave().length_counter > 0) << 1
+            | u8::from(self.triangle.as_wave().length_counter > 0) << 2
+            | u8::from(self.noise.as_wave().length_counter > 0) << 3
+            | u8::from(self.dmc.is_active()) << 4
+            | u8::from(self.frame_irq) << 6
+            | u8::from(self.dmc.irq()) << 7;
+        if !trace {
+            self.frame_irq = false;
+        }
+        data
+    }
+
+    // Levels of the IRQ outputs, held until acknowledged
+    pub fn frame_irq(&self) -> bool {
+        self.frame_irq
+    }
+
+    pub fn dmc_irq(&self) -> bool {
+        self.dmc.irq()
+    }
+
+    // Address of the sample byte the DMC needs, to be read by the bus with load_dmc_sample
+    pub fn take_dmc_request(&mut self) -> Option<u16> {
+        self.dmc.take_request()
+    }
+
+    pub fn load_dmc_sample(&mut self, data: u8) {
+        self.dmc.load_sample(data);
+    }
+
+    fn quarter_frame(&mut self) {
+        self.pulse1.process_envelope();
+        self.pulse2.process_envelope();
+        self.triangle.on_linear_count();
+        self.noise.process_envelope();
+    }
+
+    fn half_frame(&mut self) {
+        self.pulse1.on_length_count();
+        self.pulse2.on_length_count();
+        self.pulse1.process_sweep();
+        self.pulse2.process_sweep();
+        self.triangle.on_length_count();
+        self.noise.on_length_count();
+    }
+
     // https://www.nesdev.org/wiki/APU_Mixer
     fn get_pulse_output(&mut self) -> f32 {
         static PULSE_LUT: Lazy<[f32; 32]> = Lazy::new(|| {
@@ -111,7 +224,7 @@ impl Apu {
     }
 
     fn tick_single(&mut self) {
-        if TICK_SAMPLE_TIMING[self.tick] {
+        if self.sample_timing[self.sample_tick] {
             let pulse = self.get_pulse_output();
             let tnd = self.get_tnd_output();
             match self.ringbuf_prod.push(pulse + tnd) {
@@ -126,31 +239,27 @@ impl Apu {
         self.pulse2.tick(self.tick);
         self.triangle.tick(self.tick);
         self.noise.tick(self.tick);
+        self.dmc.tick(self.tick);
 
-        self.tick += 1;
-        if !self.mode {
-            static HALF_FRAME: [usize; 2] = [14913, 29829];
-            if HALF_FRAME.contains(&self.tick) {
-                self.pulse1.on_length_count();
-                self.pulse2.on_length_count();
-                self.pulse1.process_sweep();
-                self.pulse2.process_sweep();
-                self.triangle.on_length_count();
-                self.noise.on_length_count();
-            }
-
-            static QUARTER_FRAME: [usize; 4] = [7457, 14913, 22371, 29829];
-            if QUARTER_FRAME.contains(&self.tick) {
-                self.pulse1.process_envelope();
-                self.pulse2.process_envelope();
-                self.triangle.on_linear_count();
-                self.noise.process_envelope();
-            }
-        } else {
-            todo!();
+        self.sample_tick += 1;
+        if self.sample_tick == self.sample_timing.len() {
+            self.sample_tick = 0;
         }
 
-        if self.tick == TICK_SAMPLE_TIMING.len() {
+        self.tick += 1;
+        let steps = self.frame_steps;
+        // The 4-step mode ends at steps[3] and the 5-step mode at steps[4]
+        let last = if self.mode { 4 } else { 3 };
+        if steps[..3].contains(&self.tick) || self.tick == steps[last] {
+            self.quarter_frame();
+        }
+        if self.tick == steps[1] || self.tick == steps[last] {
+            self.half_frame();
+        }
+        if !self.mode && self.tick == steps[3] && !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+        if self.tick == steps[last] + 1 {
             self.tick = 0;
         }
     }
@@ -210,3 +319,107 @@ impl ApuSDL {
         }
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use crate::apu::Apu;
+    use crate::init_null_apu;
+    use crate::wave_trait::WaveTrait;
+
+    // Ticks with every DMC fetch answered by the byte
+    fn tick_with_samples(apu: &mut Apu, tick: usize, data: u8) {
+        for _ in 0..tick {
+            apu.tick_usize(1);
+            if apu.take_dmc_request().is_some() {
+                apu.load_dmc_sample(data);
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = init_null_apu();
+        apu.write_register(0x4017, 0);
+        apu.tick_usize(29828);
+        assert!(!apu.frame_irq());
+        apu.tick_usize(1);
+        assert!(apu.frame_irq());
+        // Tracing does not acknowledge
+        assert_eq!(apu.read_status(true) & 0b0100_0000, 0b0100_0000);
+        assert_eq!(apu.read_status(false) & 0b0100_0000, 0b0100_0000);
+        assert!(!apu.frame_irq());
+
+        // Inhibited, and never raised in the 5-step mode
+        apu.write_register(0x4017, 0b0100_0000);
+        apu.tick_usize(29830 * 2);
+        assert!(!apu.frame_irq());
+        apu.write_register(0x4017, 0b1000_0000);
+        apu.tick_usize(37282 * 2);
+        assert!(!apu.frame_irq());
+    }
+
+    #[test]
+    fn test_dmc_irq() {
+        let mut apu = init_null_apu();
+        apu.write_register(0x4017, 0b0100_0000);
+        // IRQ enabled, 54 cycles per bit, 17 bytes
+        apu.write_register(0x4010, 0b1000_1111);
+        apu.write_register(0x4013, 1);
+        apu.write_register(0x4015, 0b1_0000);
+        assert_eq!(apu.read_status(false), 0b1_0000);
+        assert_eq!(apu.take_dmc_request(), Some(0xc000));
+        apu.load_dmc_sample(0);
+        // The last byte is fetched after 16 bytes have been played
+        tick_with_samples(&mut apu, 15 * 8 * 54, 0);
+        assert!(!apu.dmc_irq());
+        tick_with_samples(&mut apu, 2 * 8 * 54, 0);
+        assert!(apu.dmc_irq());
+        // Reading $4015 does not acknowledge it, but writing does
+        assert_eq!(apu.read_status(false), 0b1000_0000);
+        assert!(apu.dmc_irq());
+        apu.write_register(0x4015, 0);
+        assert!(!apu.dmc_irq());
+    }
+
+    #[test]
+    fn test_dmc_sample() {
+        let mut apu = init_null_apu();
+        // 54 cycles per bit, 1 byte at $C040
+        apu.write_register(0x4010, 0b0000_1111);
+        apu.write_register(0x4012, 1);
+        apu.write_register(0x4013, 0);
+        apu.write_register(0x4015, 0b1_0000);
+        assert_eq!(apu.take_dmc_request(), Some(0xc040));
+        assert_eq!(apu.take_dmc_request(), None);
+        apu.load_dmc_sample(0xff);
+
+        // Played after the 8 silent bits of the empty shift register, 2 steps up per bit.
+        // The first bit still takes the period from power-up.
+        apu.tick_usize(428 + 7 * 54);
+        assert_eq!(apu.dmc.get_output(), 0);
+        apu.tick_usize(8 * 54);
+        assert_eq!(apu.dmc.get_output(), 16);
+        assert_eq!(apu.take_dmc_request(), None);
+    }
+
+    #[test]
+    fn test_reset_and_power_cycle() {
+        let mut apu = init_null_apu();
+        apu.write_register(0x4017, 0b1100_0000);
+        apu.write_register(0x4015, 0b1111);
+        apu.write_register(0x4003, 0b1111_1000);
+        apu.write_register(0x400f, 0b1111_1000);
+        assert_eq!(apu.read_status(false), 0b1001);
+
+        // Channels are silenced, the frame counter mode is kept
+        apu.reset();
+        assert_eq!(apu.read_status(false), 0);
+        assert!(apu.mode && apu.irq_inhibit);
+
+        // Back to the 4-step mode with the frame IRQ enabled
+        apu.power_cycle();
+        assert!(!apu.mode && !apu.irq_inhibit);
+        apu.tick_usize(29829);
+        assert!(apu.frame_irq());
+    }
+}
@@ -1,24 +1,40 @@
-use once_cell::sync::Lazy;
+use cartridge::Region;
 
+// Only for test_apu, the APU is clocked by the bus otherwise
+#[cfg(test)]
 pub static TICKS_PER_FRAME: usize = 29830;
+#[cfg(test)]
 pub static TICKS_PER_SECOND: usize = TICKS_PER_FRAME * 60;
 pub static SAMPLES_PER_SEC: i32 = 44100;
-pub static SAMPLE_PER_TICK: f64 = SAMPLES_PER_SEC as f64 / TICKS_PER_SECOND as f64;
 
 pub static LENGTH_COUNTER_LUT: [u8; 32] = [
     0x0A, 0xFE, 0x14, 0x02, 0x28, 0x04, 0x50, 0x06, 0xA0, 0x08, 0x3C, 0x0A, 0x0E, 0x0C, 0x1A, 0x0E,
     0x0C, 0x10, 0x18, 0x12, 0x30, 0x14, 0x60, 0x16, 0xC0, 0x18, 0x48, 0x1A, 0x10, 0x1C, 0x20, 0x1E,
 ];
 
-pub static TICK_SAMPLE_TIMING: Lazy<Vec<bool>> = Lazy::new(|| {
-    let mut v = vec![false; TICKS_PER_FRAME];
+// Steps of the frame sequencer in CPU cycles. The 4-step mode ends at the 4th step,
+// and the 5-step mode skips it and ends at the 5th.
+// https://www.nesdev.org/wiki/APU_Frame_Counter
+pub fn frame_sequencer_steps(region: Region) -> [usize; 5] {
+    match region {
+        Region::PAL => [8313, 16627, 24939, 33253, 41565],
+        // Dendy uses the NTSC APU
+        _ => [7457, 14913, 22371, 29829, 37281],
+    }
+}
+
+// Marks the ticks of a 4-step frame sequencer loop where an audio sample is taken
+pub fn sample_timing(region: Region) -> Vec<bool> {
+    let ticks_per_frame = frame_sequencer_steps(region)[3] + 1;
+    let sample_per_tick = SAMPLES_PER_SEC as f64 / region.cpu_freq();
+    let mut v = vec![false; ticks_per_frame];
     let mut sum = 0.0f64;
-    for i in 0..TICKS_PER_FRAME {
-        sum += SAMPLE_PER_TICK;
+    for timing in v.iter_mut() {
+        sum += sample_per_tick;
         if sum > 1.0 {
-            v[i] = true;
+            *timing = true;
             sum -= 1.0;
         }
     }
     v
-});
+}
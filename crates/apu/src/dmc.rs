@@ -1,9 +1,20 @@
 use std::ops::Shl;
 
+use cartridge::Region;
+
 use crate::wave::Wave;
 use crate::wave_trait::AsWave;
 use crate::wave_trait::WaveTrait;
 
+// Periods in CPU cycles
+// https://www.nesdev.org/wiki/APU_DMC
+static DMC_RATE_LUT: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+static DMC_RATE_LUT_PAL: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
 pub struct Dmc {
     base: Wave,
     // 0x4010
@@ -16,12 +27,19 @@ pub struct Dmc {
     sample_address: u16,
     // 0x4013
     sample_length: u16,
-    // internal
-    _rate_counter: u16,
-    start_flag: bool,
-    _sample_buffer_consuming: u8,
-    _sample_buffer_shifts: u8,
-    _length_counter_u16: u16,
+    // memory reader
+    current_address: u16,
+    bytes_remaining: u16,
+    sample_buffer: Option<u8>,
+    // Address of the next byte, until the bus has fetched it
+    request: Option<u16>,
+    // output unit
+    rate_counter: u16,
+    shift_register: u8,
+    bits_remaining: u8,
+    silence: bool,
+    irq_flag: bool,
+    rate_lut: &'static [u16; 16],
 }
 
 mod private {
@@ -47,13 +65,26 @@ impl WaveTrait for Dmc {
         self.current_output
     }
 
+    // Writing $4015 always acknowledges the DMC IRQ
     fn set_enable(&mut self, enable: bool) {
         self.as_mut_wave().enable = enable;
-        self.start_flag = enable;
+        self.irq_flag = false;
+        if !enable {
+            self.bytes_remaining = 0;
+            self.request = None;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+            self.fill_sample_buffer();
+        }
     }
 
+    // Called every CPU cycle
     fn tick(&mut self, _tick: usize) {
-        todo!()
+        self.rate_counter -= 1;
+        if self.rate_counter == 0 {
+            self.rate_counter = self.rate_lut[self.rate as usize];
+            self.clock_output();
+        }
     }
 }
 
@@ -65,16 +96,33 @@ impl Dmc {
             loop_flag: false,
             rate: 0,
             current_output: 0,
-            sample_address: 0,
-            sample_length: 0,
-            _rate_counter: 511,
-            start_flag: false,
-            _sample_buffer_consuming: 0,
-            _sample_buffer_shifts: 0,
-            _length_counter_u16: 0,
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            sample_buffer: None,
+            request: None,
+            rate_counter: DMC_RATE_LUT[0],
+            shift_register: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq_flag: false,
+            rate_lut: &DMC_RATE_LUT,
         }
     }
 
+    pub fn set_region(&mut self, region: Region) {
+        self.rate_lut = match region {
+            Region::PAL => &DMC_RATE_LUT_PAL,
+            _ => &DMC_RATE_LUT,
+        };
+    }
+
+    // Only the lowest bit of the output level survives a reset
+    pub fn reset(&mut self) {
+        self.current_output &= 1;
+    }
+
     pub fn write_0(&mut self, data: u8) {
         let enable_irq = data & 0b1000_0000 > 0;
         let loop_flag = data & 0b0100_0000 > 0;
@@ -83,6 +131,9 @@ impl Dmc {
         self.enable_irq = enable_irq;
         self.loop_flag = loop_flag;
         self.rate = rate;
+        if !enable_irq {
+            self.irq_flag = false;
+        }
     }
 
     pub fn write_1(&mut self, data: u8) {
@@ -98,52 +149,72 @@ impl Dmc {
         self.sample_length = (data as u16).shl(4) + 1u16;
     }
 
-    fn _update_output_value(&mut self, tick: usize) {
-        static RATE_INDEX: [u16; 16] = [
-            428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
-        ];
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    pub fn irq(&self) -> bool {
+        self.irq_flag
+    }
+
+    // Returns and clears the address of the sample byte the DMC is waiting for
+    pub fn take_request(&mut self) -> Option<u16> {
+        self.request.take()
+    }
 
-        if tick & 0b1 > 0 {
-            if self.start_flag {
-                self.start_flag = false;
+    // The byte fetched by the bus for the last request.
+    // The IRQ is raised as soon as the last byte is fetched, not when it has been played.
+    pub fn load_sample(&mut self, data: u8) {
+        if self.bytes_remaining == 0 {
+            return;
+        }
+        self.sample_buffer = Some(data);
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.loop_flag {
+                self.restart();
+            } else if self.enable_irq {
+                self.irq_flag = true;
             }
         }
+    }
 
-        if tick & 0b1 == 0 {
-            self._rate_counter -= 1;
-            if self._rate_counter == 0 {
-                self._rate_counter = RATE_INDEX[self.rate as usize];
-
-                if self._length_counter_u16 > 0 {
-                    let has_bit = self._sample_buffer_consuming & 0b1 > 0;
-                    let upper_limit = self.current_output > 0x7d;
-                    let lower_limit = self.current_output < 0x02;
-                    match (has_bit, upper_limit, lower_limit) {
-                        (true, false, _) => {
-                            self.current_output += 2;
-                        }
-                        (false, _, false) => {
-                            self.current_output -= 2;
-                        }
-                        _ => {}
-                    }
-                    self._sample_buffer_consuming >>= 1;
-                }
-
-                self._sample_buffer_shifts += 1;
-                // if all bits consumed
-                if self._sample_buffer_shifts == 8 {
-                    self._sample_buffer_shifts = 0;
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
 
-                    self._length_counter_u16 -= 1;
-                    self._sample_buffer_consuming = self._get_sample(self.sample_address);
-                    self.sample_address += 1;
+    fn clock_output(&mut self) {
+        if !self.silence {
+            if self.shift_register & 0b1 > 0 {
+                if self.current_output <= 0x7d {
+                    self.current_output += 2;
+                }
+            } else if self.current_output >= 0x02 {
+                self.current_output -= 2;
+            }
+        }
+        self.shift_register >>= 1;
+
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.sample_buffer.take() {
+                Some(sample) => {
+                    self.shift_register = sample;
+                    self.silence = false;
                 }
+                None => self.silence = true,
             }
+            self.fill_sample_buffer();
         }
     }
 
-    fn _get_sample(&self, _address: u16) -> u8 {
-        0
+    // The APU has no access to the CPU bus, so the fetch is left to the bus
+    fn fill_sample_buffer(&mut self) {
+        if self.sample_buffer.is_none() && self.bytes_remaining > 0 {
+            self.request = Some(self.current_address);
+        }
     }
 }
@@ -2,9 +2,15 @@ use crate::wave::Wave;
 use crate::wave_trait::AsWave;
 use crate::wave_trait::WaveTrait;
 
+use cartridge::Region;
+
+// https://www.nesdev.org/wiki/APU_Noise
 static NOISE_PERIOD_LUT: [u16; 16] = [
     4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
 ];
+static NOISE_PERIOD_LUT_PAL: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
 
 pub struct Noise {
     base: Wave,
@@ -21,6 +27,7 @@ pub struct Noise {
     envelope_counter: u8,
     period_counter: u16,
     random_value: u16,
+    period_lut: &'static [u16; 16],
 }
 
 mod private {
@@ -83,9 +90,17 @@ impl Noise {
             period: 0,
             period_counter: 1,
             random_value: 1,
+            period_lut: &NOISE_PERIOD_LUT,
         }
     }
 
+    pub fn set_region(&mut self, region: Region) {
+        self.period_lut = match region {
+            Region::PAL => &NOISE_PERIOD_LUT_PAL,
+            _ => &NOISE_PERIOD_LUT,
+        };
+    }
+
     pub fn process_envelope(&mut self) {
         if self.envelope_start_flag {
             self.envelope_start_flag = false;
@@ -140,7 +155,7 @@ impl Noise {
         if tick & 0b1 == 1 {
             self.period_counter -= 1;
             if self.period_counter == 0 {
-                self.period_counter = NOISE_PERIOD_LUT[self.period as usize];
+                self.period_counter = self.period_lut[self.period as usize];
                 let r = self.random_value;
                 self.random_value = if self.mode {
                     r.wrapping_shl(1) | ((r.wrapping_shr(14) ^ r.wrapping_shr(8)) & 0b1)
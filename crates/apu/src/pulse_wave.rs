@@ -56,10 +56,13 @@ impl WaveTrait for PulseWave {
         self.current_output
     }
 
+    // The timer is reloaded from the period once it has counted down to 0
+    // https://www.nesdev.org/wiki/APU_Pulse
     fn tick(&mut self, tick: usize) {
         if tick & 0b1 > 0 {
-            self.freq_counter -= 1;
-            if self.freq_counter == 0 {
+            if self.freq_counter > 0 {
+                self.freq_counter -= 1;
+            } else {
                 self.freq_counter = self.get_freq_11bit();
                 if self.current_duty_bit == 0 {
                     self.current_duty_bit = 7;
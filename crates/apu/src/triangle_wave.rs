@@ -41,9 +41,12 @@ impl WaveTrait for TriangleWave {
         todo!()
     }
 
+    // The timer is reloaded from the period once it has counted down to 0
+    // https://www.nesdev.org/wiki/APU_Triangle
     fn tick(&mut self, _tick: usize) {
-        self.freq_counter -= 1;
-        if self.freq_counter == 0 {
+        if self.freq_counter > 0 {
+            self.freq_counter -= 1;
+        } else {
             self.freq_counter = self.get_freq_11bit();
             if self.current_level_bit == 31 {
                 self.current_level_bit = 0;
@@ -69,6 +72,12 @@ impl TriangleWave {
         }
     }
 
+    // The sequencer restarts at the top of the waveform on reset
+    pub fn reset_phase(&mut self) {
+        self.current_level_bit = 0;
+        self.update_current_output_with_check();
+    }
+
     fn update_current_output_with_check(&mut self) {
         if self.can_output() {
             self.update_current_output();
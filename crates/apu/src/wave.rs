@@ -1,3 +1,5 @@
+use cartridge::Region;
+
 pub struct Wave {
     pub name: String,
     pub enable: bool,
@@ -6,6 +8,7 @@ pub struct Wave {
     pub reg_freq_hi: u8,
     pub length_counter: u8,
     pub phase_inc: f32,
+    pub cpu_freq: f64,
 }
 
 impl Wave {
@@ -17,6 +20,7 @@ impl Wave {
             reg_freq_hi: 0,
             length_counter: 0,
             phase_inc: 0.0,
+            cpu_freq: Region::NTSC.cpu_freq(),
             name: String::from(name),
         }
     }
@@ -1,5 +1,8 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
 use apu::{init_null_apu, Apu};
-use cartridge::Cartridge;
+use cartridge::{Cartridge, Region};
 use joypad::Joypad;
 use ppu::{Ppu, TickResult};
 
@@ -21,45 +24,117 @@ const JOYPAD_1: u16 = 0x4016;
 // const JOYPAD_2: u16 = 0x4017;
 const APU_REG: u16 = 0x4000;
 const APU_REG_END: u16 = 0x4015;
+const APU_REG_STATUS: u16 = 0x4015;
+const APU_REG_FRAME_COUNTER: u16 = 0x4017;
+
+// The CPU is halted for 4 cycles when the DMC fetches a sample byte. The shorter stalls
+// on write cycles and during OAM DMA are not emulated.
+// https://www.nesdev.org/wiki/APU_DMC#Memory_reader
+const DMC_DMA_CYCLES: u8 = 4;
 
+const PRG_RAM: u16 = 0x6000;
+const PRG_RAM_END: u16 = 0x7fff;
 pub const PRG_ROM: u16 = 0x8000;
 const PRG_ROM_END: u16 = 0xffff;
 
+// Devices pulling the shared IRQ line. Each one holds it until acknowledged on its own side,
+// e.g. reading $4015 for the APU frame counter or writing the mapper's IRQ registers.
+// https://www.nesdev.org/wiki/IRQ
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct IrqSources {
+    pub apu_frame: bool,
+    pub dmc: bool,
+    pub mapper: bool,
+}
+
+impl IrqSources {
+    pub fn any(&self) -> bool {
+        self.apu_frame || self.dmc || self.mapper
+    }
+}
+
 pub struct Bus {
     work_ram: [u8; 0x800],
-    cartridge: Cartridge,
+    pub cartridge: Rc<RefCell<Cartridge>>,
     pub ppu: Ppu,
     pub joypad1: Joypad,
     pub apu: Apu,
     // joypad2: Joypad,
     cycles: usize,
-    should_intr_nmi: bool,
+    // NMI is edge triggered, so it is latched until the CPU takes it
+    nmi_pending: bool,
+    frame_ready: bool,
+    oam_dma: Option<u8>,
+    region: Region,
+    // Fraction of a PPU dot left over on PAL
+    ppu_dot_remainder: usize,
 }
 
 impl Bus {
     pub fn new() -> Self {
+        let cartridge = Rc::new(RefCell::new(Cartridge::new()));
         Bus {
             work_ram: [0; 0x800],
-            cartridge: Cartridge::new(),
-            ppu: Ppu::new(),
+            ppu: Ppu::load_cartridge(cartridge.clone()),
+            cartridge,
             cycles: 0,
-            should_intr_nmi: false,
+            nmi_pending: false,
+            frame_ready: false,
+            oam_dma: None,
+            region: Region::NTSC,
+            ppu_dot_remainder: 0,
             joypad1: Joypad::new(),
             // joypad2: Joypad::new(),
             apu: init_null_apu(),
         }
     }
 
+    // The region follows the header of the cartridge
     pub fn load_cartridge(&mut self, cartridge: Cartridge) {
-        self.cartridge = cartridge;
-        self.ppu = Ppu::load_cartridge(
-            self.cartridge.chr_rom.clone(),
-            self.cartridge.screen_mirroring,
-        );
+        let region = Region::from_video_signal(cartridge.header.video_signal);
+        self.cartridge = Rc::new(RefCell::new(cartridge));
+        self.ppu = Ppu::load_cartridge(self.cartridge.clone());
+        self.set_region(region);
     }
 
     pub fn associate_apu(&mut self, apu: Apu) {
         self.apu = apu;
+        self.apu.set_region(self.region);
+    }
+
+    // The reset button only reaches the PPU, the APU and the mapper. Work RAM is kept.
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        self.apu.reset();
+        self.cartridge.borrow_mut().reset();
+        self.joypad1.reset();
+        self.nmi_pending = false;
+        self.frame_ready = false;
+        self.oam_dma = None;
+    }
+
+    pub fn power_cycle(&mut self) {
+        self.work_ram.fill(0);
+        self.ppu.power_cycle();
+        self.apu.power_cycle();
+        self.cartridge.borrow_mut().power_cycle();
+        self.joypad1.reset();
+        self.cycles = 0;
+        self.nmi_pending = false;
+        self.frame_ready = false;
+        self.oam_dma = None;
+        self.ppu_dot_remainder = 0;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu_dot_remainder = 0;
+        self.ppu.set_region(region);
+        self.apu.set_region(region);
     }
 
     #[must_use]
@@ -86,13 +161,12 @@ impl Bus {
             PPU_REG_STATUS => self.ppu.read_stat(trace),
             PPU_REG_OAM_DATA => self.ppu.read_oam_data(),
             PPU_REG_DATA => self.ppu.read_data(trace),
+            APU_REG_STATUS => self.apu.read_status(trace),
+            PRG_RAM..=PRG_RAM_END => self.cartridge.borrow().read_prg_ram(address),
             PRG_ROM..=PRG_ROM_END => {
-                if self.cartridge.loaded {
-                    let mut address = address - 0x8000;
-                    if self.cartridge.prg_rom.len() == 0x4000 && address >= 0x4000 {
-                        address -= 0x4000;
-                    }
-                    self.cartridge.prg_rom[address as usize]
+                let cartridge = self.cartridge.borrow();
+                if cartridge.loaded {
+                    cartridge.read_prg(address)
                 } else {
                     panic!("Invalid read of {:X}", address);
                 }
@@ -116,7 +190,7 @@ impl Bus {
             PPU_REG_CTRL => {
                 let tick_result = self.ppu.write_ctrl(data);
                 if tick_result == TickResult::ShouldInterruptNmiAndUpdateTexture {
-                    self.should_intr_nmi = true;
+                    self.nmi_pending = true;
                 }
             }
             PPU_REG_MASK => self.ppu.write_mask(data),
@@ -126,20 +200,15 @@ impl Bus {
             PPU_REG_SCROLL => self.ppu.write_scrl(data),
             PPU_REG_ADDRESS => self.ppu.write_addr(data),
             PPU_REG_DATA => self.ppu.write_data(data),
-            PPU_REG_OAM_DMA => {
-                let dma_start = ((data as u16) << 8) as usize;
-                let dma_end = dma_start + 0xff;
-                let slice = &self.work_ram[dma_start..=dma_end];
-                self.ppu.oam_data.copy_from_slice(slice);
-            }
+            // Copied by the CPU through PPU_REG_OAM_DATA, which is halted meanwhile
+            PPU_REG_OAM_DMA => self.oam_dma = Some(data),
             PPU_REGISTERS_MIRRORS..=PPU_REGISTERS_MIRRORS_END => {
                 let address = address & 0b0010_0000_0000_0111;
                 self.write8(address, data);
             }
-            PRG_ROM..=PRG_ROM_END => {
-                panic!("Invalid write of {:X}", address);
-            }
-            APU_REG..=APU_REG_END => self.apu.write_register(address, data),
+            PRG_RAM..=PRG_RAM_END => self.cartridge.borrow_mut().write_prg_ram(address, data),
+            PRG_ROM..=PRG_ROM_END => self.cartridge.borrow_mut().write_prg(address, data),
+            APU_REG..=APU_REG_END | APU_REG_FRAME_COUNTER => self.apu.write_register(address, data),
             JOYPAD_1 => self.joypad1.write(data),
             //JOYPAD_2 => self.joypad2.write(data),
             _ => {} // No-op if out of range
@@ -175,10 +244,63 @@ impl Bus {
         self.work_ram[range].copy_from_slice(&data);
     }
 
+    pub fn irq_sources(&self) -> IrqSources {
+        IrqSources {
+            apu_frame: self.apu.frame_irq(),
+            dmc: self.apu.dmc_irq(),
+            mapper: self.cartridge.borrow().irq(),
+        }
+    }
+
+    // Level of the IRQ line
+    pub fn irq(&self) -> bool {
+        self.irq_sources().any()
+    }
+
+    // Returns and clears the NMI latched since the last call
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi_pending)
+    }
+
+    // Returns and clears whether the PPU has finished a frame since the last call
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    // Returns and clears the page written to $4014
+    pub fn take_oam_dma(&mut self) -> Option<u8> {
+        self.oam_dma.take()
+    }
+
+    // The DMC fetches are done here, in extra cycles that the CPU does not count
     pub fn tick(&mut self, cycles: u8) -> Vec<TickResult> {
+        let mut tick_results = self.tick_devices(cycles);
+        if let Some(address) = self.apu.take_dmc_request() {
+            tick_results.extend(self.tick_devices(DMC_DMA_CYCLES));
+            let data = self.read8(address);
+            self.apu.load_dmc_sample(data);
+        }
+        tick_results
+    }
+
+    fn tick_devices(&mut self, cycles: u8) -> Vec<TickResult> {
         self.cycles += cycles as usize;
         self.apu.tick(cycles);
-        return self.ppu.tick(cycles * 3);
+        let (num, den) = self.region.ppu_dots_per_cpu_cycle();
+        let dots = cycles as usize * num + self.ppu_dot_remainder;
+        self.ppu_dot_remainder = dots % den;
+        let tick_results = self.ppu.tick(dots / den);
+        for result in &tick_results {
+            match result {
+                TickResult::ShouldInterruptNmiAndUpdateTexture => {
+                    self.nmi_pending = true;
+                    self.frame_ready = true;
+                }
+                TickResult::ShouldUpdateTexture => self.frame_ready = true,
+                _ => {}
+            }
+        }
+        tick_results
     }
 }
 
@@ -187,3 +309,42 @@ impl Default for Bus {
         Bus::new()
     }
 }
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_pal_ppu_clock_ratio() {
+        let mut bus = Bus::new();
+        bus.set_region(Region::PAL);
+        let (start, _) = bus.ppu.get_cycles_scanlines();
+        // 5 CPU cycles are exactly 16 dots
+        for _ in 0..5 {
+            bus.tick(1);
+        }
+        assert_eq!(bus.ppu.get_cycles_scanlines().0, start + 16);
+    }
+
+    #[test]
+    fn test_dmc_dma() {
+        let mut raw = vec![b'N', b'E', b'S', 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.resize(16 + 0x4000 + 0x2000, 0);
+        raw[16 + 0x0040] = 0x55;
+        let mut bus = Bus::new();
+        bus.load_cartridge(Cartridge::load(&raw).unwrap());
+
+        // One byte sample at $C040
+        bus.write8(0x4012, 1);
+        bus.write8(0x4013, 0);
+        bus.write8(0x4015, 0b1_0000);
+        assert_eq!(bus.read8(0x4015) & 0b1_0000, 0b1_0000);
+
+        // The fetch stalls the CPU, so the PPU runs 3 dots for each of the 5 cycles
+        let (start, _) = bus.ppu.get_cycles_scanlines();
+        bus.tick(1);
+        assert_eq!(bus.ppu.get_cycles_scanlines().0, start + 15);
+        assert_eq!(bus.read8(0x4015) & 0b1_0000, 0);
+        assert_eq!(bus.apu.take_dmc_request(), None);
+    }
+}
@@ -0,0 +1,239 @@
+use std::borrow::Cow;
+
+use miniz_oxide::inflate::decompress_to_vec;
+
+use crate::hash::crc32;
+use crate::CartridgeError;
+
+// https://pkware.cachefly.net/webdocs/casestudies/APPNOTE.TXT
+const ZIP_LOCAL_HEADER: u32 = 0x0403_4b50;
+const ZIP_CENTRAL_HEADER: u32 = 0x0201_4b50;
+const ZIP_END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+// https://datatracker.ietf.org/doc/html/rfc1952
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+struct ZipEntry {
+    name: String,
+    method: u16,
+    crc32: u32,
+    compressed_size: usize,
+    local_header_offset: usize,
+}
+
+// Returns the .nes image inside a zip or gzip archive, or the data as is if it is not an archive.
+// A zip holding several ROMs needs the entry name.
+pub fn extract_rom<'a>(data: &'a [u8], name: Option<&str>) -> Result<Cow<'a, [u8]>, CartridgeError> {
+    if data.len() >= 4 && read_u32(data, 0) == ZIP_LOCAL_HEADER {
+        extract_zip(data, name).map(Cow::Owned)
+    } else if data.starts_with(&GZIP_MAGIC) {
+        extract_gzip(data).map(Cow::Owned)
+    } else {
+        Ok(Cow::Borrowed(data))
+    }
+}
+
+fn extract_zip(data: &[u8], name: Option<&str>) -> Result<Vec<u8>, CartridgeError> {
+    let entries = zip_entries(data)?;
+    let candidates: Vec<&ZipEntry> = entries
+        .iter()
+        .filter(|entry| entry.name.to_lowercase().ends_with(".nes"))
+        .collect();
+    let candidate_names = || candidates.iter().map(|entry| entry.name.clone()).collect();
+
+    let entry = match name {
+        Some(name) => entries
+            .iter()
+            .find(|entry| entry.name == name || entry.name.rsplit('/').next() == Some(name))
+            .ok_or_else(|| CartridgeError::RomNotInArchive(candidate_names()))?,
+        None => match candidates.as_slice() {
+            [entry] => *entry,
+            _ => return Err(CartridgeError::RomNotInArchive(candidate_names())),
+        },
+    };
+
+    // The local header may have a different extra field length than the central one
+    let offset = entry.local_header_offset;
+    if data.len() < offset + 30 || read_u32(data, offset) != ZIP_LOCAL_HEADER {
+        return Err(CartridgeError::BadArchive("Broken zip local header"));
+    }
+    let start = offset + 30 + read_u16(data, offset + 26) as usize + read_u16(data, offset + 28) as usize;
+    let compressed = data
+        .get(start..start + entry.compressed_size)
+        .ok_or(CartridgeError::BadArchive("Truncated zip entry"))?;
+
+    let rom = match entry.method {
+        0 => compressed.to_vec(),
+        8 => inflate(compressed)?,
+        _ => return Err(CartridgeError::BadArchive("Unsupported zip compression method")),
+    };
+    if crc32(&rom) != entry.crc32 {
+        return Err(CartridgeError::BadArchive("CRC32 mismatch in zip entry"));
+    }
+    Ok(rom)
+}
+
+fn zip_entries(data: &[u8]) -> Result<Vec<ZipEntry>, CartridgeError> {
+    // The end of central directory record is followed by a comment of up to 64KB
+    let eocd = (0..data.len().saturating_sub(21))
+        .rev()
+        .take(0x10000 + 22)
+        .find(|i| read_u32(data, *i) == ZIP_END_OF_CENTRAL_DIRECTORY)
+        .ok_or(CartridgeError::BadArchive("Zip has no central directory"))?;
+
+    let count = read_u16(data, eocd + 10) as usize;
+    let mut offset = read_u32(data, eocd + 16) as usize;
+    let mut entries = vec![];
+    for _ in 0..count {
+        if data.len() < offset + 46 || read_u32(data, offset) != ZIP_CENTRAL_HEADER {
+            return Err(CartridgeError::BadArchive("Broken zip central directory"));
+        }
+        let name_len = read_u16(data, offset + 28) as usize;
+        let extra_len = read_u16(data, offset + 30) as usize;
+        let comment_len = read_u16(data, offset + 32) as usize;
+        let name = data
+            .get(offset + 46..offset + 46 + name_len)
+            .ok_or(CartridgeError::BadArchive("Broken zip central directory"))?;
+        entries.push(ZipEntry {
+            name: String::from_utf8_lossy(name).into_owned(),
+            method: read_u16(data, offset + 10),
+            crc32: read_u32(data, offset + 16),
+            compressed_size: read_u32(data, offset + 20) as usize,
+            local_header_offset: read_u32(data, offset + 42) as usize,
+        });
+        offset += 46 + name_len + extra_len + comment_len;
+    }
+    Ok(entries)
+}
+
+fn extract_gzip(data: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    const FHCRC: u8 = 0b0_0010;
+    const FEXTRA: u8 = 0b0_0100;
+    const FNAME: u8 = 0b0_1000;
+    const FCOMMENT: u8 = 0b1_0000;
+    let broken = CartridgeError::BadArchive("Broken gzip header");
+
+    if data.len() < 18 || data[2] != 8 {
+        return Err(broken);
+    }
+    let flags = data[3];
+    let mut offset = 10;
+    if flags & FEXTRA > 0 {
+        offset += 2 + read_u16(data, offset) as usize;
+    }
+    for flag in [FNAME, FCOMMENT] {
+        if flags & flag > 0 {
+            // Zero terminated string
+            offset += data.get(offset..).and_then(|s| s.iter().position(|b| *b == 0)).ok_or(broken.clone())? + 1;
+        }
+    }
+    if flags & FHCRC > 0 {
+        offset += 2;
+    }
+    if data.len() < offset + 8 {
+        return Err(broken);
+    }
+
+    let rom = inflate(&data[offset..data.len() - 8])?;
+    if crc32(&rom) != read_u32(data, data.len() - 8) {
+        return Err(CartridgeError::BadArchive("CRC32 mismatch in gzip"));
+    }
+    Ok(rom)
+}
+
+fn inflate(data: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    decompress_to_vec(data).map_err(|_| CartridgeError::BadArchive("Broken deflate stream"))
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    // Builds a zip of stored entries
+    fn zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut data = vec![];
+        let mut central = vec![];
+        for (name, content) in files {
+            let offset = data.len() as u32;
+            let crc = crc32(content);
+            data.extend_from_slice(&ZIP_LOCAL_HEADER.to_le_bytes());
+            data.extend_from_slice(&[20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            data.extend_from_slice(&crc.to_le_bytes());
+            data.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            data.extend_from_slice(&[0, 0]);
+            data.extend_from_slice(name.as_bytes());
+            data.extend_from_slice(content);
+
+            central.extend_from_slice(&ZIP_CENTRAL_HEADER.to_le_bytes());
+            central.extend_from_slice(&[20, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+            central.extend_from_slice(&crc.to_le_bytes());
+            central.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            central.extend_from_slice(&(content.len() as u32).to_le_bytes());
+            central.extend_from_slice(&(name.len() as u16).to_le_bytes());
+            central.extend_from_slice(&[0; 12]);
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(name.as_bytes());
+        }
+        let central_offset = data.len() as u32;
+        data.extend_from_slice(&central);
+        data.extend_from_slice(&ZIP_END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(files.len() as u16).to_le_bytes());
+        data.extend_from_slice(&(central.len() as u32).to_le_bytes());
+        data.extend_from_slice(&central_offset.to_le_bytes());
+        data.extend_from_slice(&[0, 0]);
+        data
+    }
+
+    #[test]
+    fn test_extract_zip() {
+        let data = zip(&[("readme.txt", b"hello"), ("roms/game.nes", b"NES\x1a")]);
+        assert_eq!(extract_rom(&data, None).unwrap().as_ref(), b"NES\x1a");
+        assert_eq!(extract_rom(&data, Some("game.nes")).unwrap().as_ref(), b"NES\x1a");
+        assert_eq!(extract_rom(&data, Some("readme.txt")).unwrap().as_ref(), b"hello");
+    }
+
+    #[test]
+    fn test_extract_zip_candidates() {
+        let data = zip(&[("a.nes", b"A"), ("b.NES", b"B")]);
+        assert_eq!(
+            extract_rom(&data, None).err(),
+            Some(CartridgeError::RomNotInArchive(vec!["a.nes".to_string(), "b.NES".to_string()]))
+        );
+        assert_eq!(extract_rom(&data, Some("b.NES")).unwrap().as_ref(), b"B");
+        assert!(extract_rom(&data, Some("c.nes")).is_err());
+    }
+
+    #[test]
+    fn test_extract_gzip() {
+        let rom = b"NES\x1a NES\x1a NES\x1a NES\x1a";
+        // With FNAME flag
+        let mut data = vec![0x1f, 0x8b, 8, 0b0_1000, 0, 0, 0, 0, 0, 3];
+        data.extend_from_slice(b"game.nes\0");
+        data.extend_from_slice(&miniz_oxide::deflate::compress_to_vec(rom, 6));
+        data.extend_from_slice(&crc32(rom).to_le_bytes());
+        data.extend_from_slice(&(rom.len() as u32).to_le_bytes());
+        assert_eq!(extract_rom(&data, None).unwrap().as_ref(), rom);
+
+        // Corrupted trailer
+        let len = data.len();
+        data[len - 8] ^= 1;
+        assert!(extract_rom(&data, None).is_err());
+    }
+
+    #[test]
+    fn test_raw_rom_is_passed_through() {
+        let data = b"NES\x1a".to_vec();
+        assert!(matches!(extract_rom(&data, None).unwrap(), Cow::Borrowed(_)));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::mapper::{bank_offset, Mapper, PRG_BANK_32K};
+use crate::Mirroring;
+
+// https://www.nesdev.org/wiki/AxROM
+// Only AMROM (submapper 2) has bus conflicts, and games made for ANROM/AOROM
+// break if they are emulated, so AxROM writes are taken as is by default.
+pub struct Axrom {
+    prg_rom_size: usize,
+    prg_bank: u8,
+    mirroring: Mirroring,
+    bus_conflicts: bool,
+}
+
+impl Axrom {
+    pub fn new(prg_rom_size: usize, bus_conflicts: bool) -> Self {
+        Axrom {
+            prg_rom_size,
+            prg_bank: 0,
+            mirroring: Mirroring::SingleScreenA,
+            bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let bank = (self.prg_bank & 0b0111) as usize;
+        bank_offset(bank, PRG_BANK_32K, self.prg_rom_size) + (address - 0x8000) as usize
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        address as usize
+    }
+
+    fn write_register(&mut self, _address: u16, data: u8) {
+        self.prg_bank = data;
+        // Selects the nametable used for all four screens
+        self.mirroring = match data & 0b1_0000 {
+            0 => Mirroring::SingleScreenA,
+            _ => Mirroring::SingleScreenB,
+        };
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(self.mirroring)
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_axrom_banks_and_mirroring() {
+        let mut axrom = Axrom::new(0x40000, false);
+        assert_eq!(axrom.mirroring(), Some(Mirroring::SingleScreenA));
+        axrom.write_register(0x8000, 0b1_0101);
+        assert_eq!(axrom.prg_rom_offset(0x8000), 0x28000);
+        assert_eq!(axrom.prg_rom_offset(0xffff), 0x2ffff);
+        assert_eq!(axrom.mirroring(), Some(Mirroring::SingleScreenB));
+    }
+}
@@ -0,0 +1,52 @@
+use crate::mapper::{bank_offset, Mapper, CHR_BANK_8K};
+
+// https://www.nesdev.org/wiki/CNROM
+pub struct Cnrom {
+    prg_rom_size: usize,
+    chr_size: usize,
+    chr_bank: u8,
+    bus_conflicts: bool,
+}
+
+impl Cnrom {
+    pub fn new(prg_rom_size: usize, chr_size: usize, bus_conflicts: bool) -> Self {
+        Cnrom {
+            prg_rom_size,
+            chr_size,
+            chr_bank: 0,
+            bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Cnrom {
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        // Same as NROM
+        ((address - 0x8000) as usize) % self.prg_rom_size.max(1)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        bank_offset(self.chr_bank as usize, CHR_BANK_8K, self.chr_size) + address as usize
+    }
+
+    fn write_register(&mut self, _address: u16, data: u8) {
+        self.chr_bank = data;
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_cnrom_chr_banks() {
+        let mut cnrom = Cnrom::new(0x4000, 0x8000, true);
+        assert_eq!(cnrom.prg_rom_offset(0xc010), 0x0010);
+        cnrom.write_register(0x8000, 3);
+        assert_eq!(cnrom.chr_offset(0x1010), 0x7010);
+    }
+}
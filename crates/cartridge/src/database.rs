@@ -0,0 +1,221 @@
+use std::fmt::Debug;
+use std::path::Path;
+
+use crate::hash::{crc32, sha1};
+use crate::{Mirroring, RomHeader, VideoSignal};
+
+// https://www.nesdev.org/wiki/NES_2.0_XML_Database
+// Entries are identified by the CRC32 (and SHA-1 if given) of PRG-ROM + CHR-ROM.
+#[derive(Debug, Clone, Default)]
+pub struct GameEntry {
+    pub crc32: u32,
+    pub sha1: Option<[u8; 20]>,
+    pub mapper: u16,
+    pub submapper: u8,
+    // None if the mapper controls mirroring
+    pub mirroring: Option<Mirroring>,
+    pub battery: bool,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub video_signal: Option<VideoSignal>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GameDatabase {
+    pub entries: Vec<GameEntry>,
+}
+
+impl GameDatabase {
+    pub fn new() -> Self {
+        GameDatabase { entries: vec![] }
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<GameDatabase> {
+        Ok(GameDatabase::parse(&std::fs::read_to_string(path)?))
+    }
+
+    // Games without <rom> or <pcb> are skipped
+    pub fn parse(xml: &str) -> GameDatabase {
+        let mut entries = vec![];
+        for game in xml.split("<game>").skip(1) {
+            let game = game.split("</game>").next().unwrap_or("");
+            let mut entry = GameEntry::default();
+            let mut has_rom = false;
+            let mut has_pcb = false;
+
+            for (name, attrs) in tags(game) {
+                let attr = |key: &str| attrs.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str());
+                let size = attr("size").and_then(|v| v.parse().ok()).unwrap_or(0);
+                match name {
+                    "rom" => {
+                        has_rom = true;
+                        entry.crc32 = attr("crc32").and_then(|v| u32::from_str_radix(v, 16).ok()).unwrap_or(0);
+                        entry.sha1 = attr("sha1").and_then(parse_sha1);
+                    }
+                    "pcb" => {
+                        has_pcb = true;
+                        entry.mapper = attr("mapper").and_then(|v| v.parse().ok()).unwrap_or(0);
+                        entry.submapper = attr("submapper").and_then(|v| v.parse().ok()).unwrap_or(0);
+                        entry.battery = attr("battery") == Some("1");
+                        entry.mirroring = match attr("mirroring") {
+                            Some("H") => Some(Mirroring::Horizontal),
+                            Some("V") => Some(Mirroring::Vertical),
+                            Some("4") => Some(Mirroring::FourScreen),
+                            _ => None,
+                        };
+                    }
+                    "console" => {
+                        entry.video_signal = match attr("region") {
+                            Some("0") => Some(VideoSignal::NTSC),
+                            Some("1") => Some(VideoSignal::PAL),
+                            Some("2") => Some(VideoSignal::MultiRegion),
+                            Some("3") => Some(VideoSignal::Dendy),
+                            _ => None,
+                        };
+                    }
+                    "prgram" => entry.prg_ram_size = size,
+                    "prgnvram" => entry.prg_nvram_size = size,
+                    "chrram" => entry.chr_ram_size = size,
+                    "chrnvram" => entry.chr_nvram_size = size,
+                    _ => {}
+                }
+            }
+
+            if has_rom && has_pcb {
+                entries.push(entry);
+            }
+        }
+        GameDatabase { entries }
+    }
+
+    // rom is PRG-ROM followed by CHR-ROM, without header and trainer
+    pub fn find(&self, rom: &[u8]) -> Option<&GameEntry> {
+        let crc = crc32(rom);
+        let mut digest = None;
+        self.entries.iter().find(|entry| {
+            if entry.crc32 != crc {
+                return false;
+            }
+            match entry.sha1 {
+                Some(expected) => *digest.get_or_insert_with(|| sha1(rom)) == expected,
+                None => true,
+            }
+        })
+    }
+}
+
+impl GameEntry {
+    // Overwrites the header with the database, logging every field that changed
+    pub fn apply(&self, header: &mut RomHeader, log: &mut impl FnMut(&str)) {
+        log(&format!("database: matched crc32 {:08X}", self.crc32));
+        update("mapper", &mut header.mapper, self.mapper, log);
+        update("submapper", &mut header.submapper, self.submapper, log);
+        if let Some(mirroring) = self.mirroring {
+            update("mirroring", &mut header.mirroring, mirroring, log);
+        }
+        update("battery", &mut header.battery, self.battery, log);
+        update("prg_ram_size", &mut header.prg_ram_size, self.prg_ram_size, log);
+        update("prg_nvram_size", &mut header.prg_nvram_size, self.prg_nvram_size, log);
+        update("chr_ram_size", &mut header.chr_ram_size, self.chr_ram_size, log);
+        update("chr_nvram_size", &mut header.chr_nvram_size, self.chr_nvram_size, log);
+        if let Some(video_signal) = self.video_signal {
+            update("video_signal", &mut header.video_signal, video_signal, log);
+        }
+    }
+}
+
+fn update<T: PartialEq + Debug>(name: &str, field: &mut T, value: T, log: &mut impl FnMut(&str)) {
+    if *field != value {
+        log(&format!("database: {} {:?} -> {:?}", name, field, value));
+        *field = value;
+    }
+}
+
+// Yields the name and attributes of each element, e.g. <pcb mapper="1"/>
+fn tags(xml: &str) -> impl Iterator<Item = (&str, Vec<(String, String)>)> {
+    xml.split('<').skip(1).filter_map(|tag| {
+        let tag = tag.split('>').next()?.trim_end_matches('/');
+        if tag.starts_with(['!', '/', '?']) {
+            return None;
+        }
+        let (name, mut rest) = tag.split_once(char::is_whitespace).unwrap_or((tag, ""));
+        let mut attrs = vec![];
+        while let Some((key, value)) = rest.split_once("=\"") {
+            let (value, next) = value.split_once('"')?;
+            attrs.push((key.trim().to_string(), value.to_string()));
+            rest = next;
+        }
+        Some((name, attrs))
+    })
+}
+
+fn parse_sha1(hex: &str) -> Option<[u8; 20]> {
+    if hex.len() != 40 {
+        return None;
+    }
+    let mut digest = [0u8; 20];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(digest)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    const XML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<nes20db>
+<game>
+  <!-- Test game -->
+  <prgrom size="9" crc32="CBF43926"/>
+  <rom size="9" crc32="CBF43926" sha1="F7C3BC1D808E04732ADF679965CCC34CA7AE3441"/>
+  <console type="0" region="1"/>
+  <pcb mapper="1" submapper="0" mirroring="H" battery="1"/>
+  <prgnvram size="8192"/>
+  <chrram size="8192"/>
+</game>
+<game>
+  <rom size="3" crc32="352441C2"/>
+</game>
+</nes20db>
+"#;
+
+    #[test]
+    fn test_parse_database() {
+        let db = GameDatabase::parse(XML);
+        assert_eq!(db.entries.len(), 1);
+        let entry = &db.entries[0];
+        assert_eq!(entry.crc32, 0xcbf4_3926);
+        assert_eq!(entry.sha1.unwrap()[0], 0xf7);
+        assert_eq!(entry.mapper, 1);
+        assert_eq!(entry.mirroring, Some(Mirroring::Horizontal));
+        assert!(entry.battery);
+        assert_eq!(entry.prg_nvram_size, 0x2000);
+        assert_eq!(entry.video_signal, Some(VideoSignal::PAL));
+    }
+
+    #[test]
+    fn test_find_and_apply() {
+        let db = GameDatabase::parse(XML);
+        assert!(db.find(b"12345678").is_none());
+        let entry = db.find(b"123456789").unwrap();
+
+        let mut header = RomHeader {
+            mapper: 4,
+            mirroring: Mirroring::Vertical,
+            prg_ram_size: 0x2000,
+            ..Default::default()
+        };
+        let mut lines = vec![];
+        entry.apply(&mut header, &mut |line| lines.push(line.to_string()));
+        assert_eq!(header.mapper, 1);
+        assert_eq!(header.mirroring, Mirroring::Horizontal);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+        assert!(lines.contains(&"database: mapper 4 -> 1".to_string()));
+        assert!(!lines.iter().any(|line| line.contains("submapper")));
+    }
+}
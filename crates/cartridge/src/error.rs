@@ -0,0 +1,45 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum CartridgeError {
+    // The file does not start with "NES^Z"
+    BadMagic,
+    // The file is shorter than the sizes in the header
+    TruncatedPrg { expected: usize, actual: usize },
+    TruncatedChr { expected: usize, actual: usize },
+    UnsupportedMapper(u16),
+    // The mirroring in the header can not be wired on the board
+    InvalidMirroring,
+    // Corrupted zip or gzip
+    BadArchive(&'static str),
+    // The archive has none or several ROMs, or not the requested one. Holds the .nes files in it
+    RomNotInArchive(Vec<String>),
+    // Malformed IPS/UPS/BPS, or made for another ROM
+    BadPatch(&'static str),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::BadMagic => write!(f, "Header did not NES^Z"),
+            CartridgeError::TruncatedPrg { expected, actual } => {
+                write!(f, "PRG-ROM is truncated: expected 0x{:X} bytes, got 0x{:X}", expected, actual)
+            }
+            CartridgeError::TruncatedChr { expected, actual } => {
+                write!(f, "CHR-ROM is truncated: expected 0x{:X} bytes, got 0x{:X}", expected, actual)
+            }
+            CartridgeError::UnsupportedMapper(mapper) => write!(f, "Unsupported mapper {}", mapper),
+            CartridgeError::InvalidMirroring => write!(f, "Invalid screen mirroring type"),
+            CartridgeError::BadArchive(reason) => write!(f, "{}", reason),
+            CartridgeError::RomNotInArchive(candidates) if candidates.is_empty() => {
+                write!(f, "No .nes file in the archive")
+            }
+            CartridgeError::RomNotInArchive(candidates) => {
+                write!(f, "Pick a ROM in the archive: {}", candidates.join(", "))
+            }
+            CartridgeError::BadPatch(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
@@ -0,0 +1,218 @@
+use crate::{CartridgeError, Mirroring, VideoSignal};
+
+// https://www.nesdev.org/wiki/INES
+// https://www.nesdev.org/wiki/NES_2.0
+pub const HEADER_SIZE: usize = 0x10;
+pub const TRAINER_SIZE: usize = 0x200;
+const NES_TAG: [u8; 4] = [b'N', b'E', b'S', 0x1a];
+
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub enum HeaderFormat {
+    #[default]
+    INes,
+    Nes20,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub enum ConsoleType {
+    #[default]
+    Nes,
+    VsSystem,
+    Playchoice10,
+    // Byte 13 of NES 2.0 header
+    Extended(u8),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RomHeader {
+    pub format: HeaderFormat,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub trainer: bool,
+    // All sizes are in bytes
+    pub prg_rom_size: usize,
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub console_type: ConsoleType,
+    pub video_signal: VideoSignal,
+    pub misc_roms: u8,
+    pub expansion_device: u8,
+}
+
+impl RomHeader {
+    pub fn parse(raw: &[u8]) -> Result<RomHeader, CartridgeError> {
+        if raw.len() < HEADER_SIZE || raw[0..4] != NES_TAG {
+            return Err(CartridgeError::BadMagic);
+        }
+
+        let mirroring = match raw[6] & 0b0000_1001 {
+            0b0000_0000 => Mirroring::Horizontal,
+            0b0000_0001 => Mirroring::Vertical,
+            _ => Mirroring::FourScreen,
+        };
+
+        let console_type = match raw[7] & 0b11 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(raw[13] & 0b1111),
+        };
+
+        let mut header = RomHeader {
+            mapper: ((raw[6] >> 4) | (raw[7] & 0b1111_0000)) as u16,
+            mirroring,
+            battery: raw[6] & 0b0010 > 0,
+            trainer: raw[6] & 0b0100 > 0,
+            prg_rom_size: 0x4000 * raw[4] as usize,
+            chr_rom_size: 0x2000 * raw[5] as usize,
+            console_type,
+            ..Default::default()
+        };
+
+        if raw[7] & 0b0000_1100 == 0b0000_1000 {
+            header.parse_nes20(raw);
+        } else {
+            header.parse_ines(raw);
+        }
+        Ok(header)
+    }
+
+    fn parse_ines(&mut self, raw: &[u8]) {
+        self.format = HeaderFormat::INes;
+
+        // Old dumpers wrote their name to bytes 7-15, e.g. "DiskDude!",
+        // so everything past byte 6 is garbage
+        let garbage = raw[12..16].iter().any(|b| *b != 0);
+        if garbage {
+            self.mapper &= 0b1111;
+            self.console_type = ConsoleType::Nes;
+        }
+
+        // 0 infers 8KB for compatibility
+        let prg_ram_banks = if garbage { 1 } else { (raw[8] as usize).max(1) };
+        if self.battery {
+            self.prg_nvram_size = 0x2000 * prg_ram_banks;
+        } else {
+            self.prg_ram_size = 0x2000 * prg_ram_banks;
+        }
+        // Zero CHR-ROM banks means the board has 8KB CHR-RAM instead
+        if self.chr_rom_size == 0 {
+            self.chr_ram_size = 0x2000;
+        }
+        self.video_signal = match (garbage, raw[9] & 1) {
+            (false, 1) => VideoSignal::PAL,
+            _ => VideoSignal::NTSC,
+        };
+    }
+
+    fn parse_nes20(&mut self, raw: &[u8]) {
+        self.format = HeaderFormat::Nes20;
+        self.mapper |= ((raw[8] & 0b1111) as u16) << 8;
+        self.submapper = raw[8] >> 4;
+
+        self.prg_rom_size = rom_size(raw[4], raw[9] & 0b1111, 0x4000);
+        self.chr_rom_size = rom_size(raw[5], raw[9] >> 4, 0x2000);
+        self.prg_ram_size = ram_size(raw[10] & 0b1111);
+        self.prg_nvram_size = ram_size(raw[10] >> 4);
+        self.chr_ram_size = ram_size(raw[11] & 0b1111);
+        self.chr_nvram_size = ram_size(raw[11] >> 4);
+
+        self.video_signal = match raw[12] & 0b11 {
+            0 => VideoSignal::NTSC,
+            1 => VideoSignal::PAL,
+            2 => VideoSignal::MultiRegion,
+            _ => VideoSignal::Dendy,
+        };
+        self.misc_roms = raw[14] & 0b11;
+        self.expansion_device = raw[15] & 0b0011_1111;
+    }
+
+    // Offset of PRG-ROM in the file
+    pub fn prg_rom_start(&self) -> usize {
+        HEADER_SIZE + if self.trainer { TRAINER_SIZE } else { 0 }
+    }
+
+    // Saturates like the sizes, which can't fit in any file then
+    pub fn chr_rom_start(&self) -> usize {
+        self.prg_rom_start().saturating_add(self.prg_rom_size)
+    }
+}
+
+fn rom_size(lsb: u8, msb: u8, unit: usize) -> usize {
+    if msb == 0b1111 {
+        // Exponent-multiplier notation: 2^E * (MM * 2 + 1)
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        2usize.saturating_pow(exponent).saturating_mul(multiplier)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * unit
+    }
+}
+
+fn ram_size(shift_count: u8) -> usize {
+    match shift_count {
+        0 => 0,
+        _ => 64 << shift_count,
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_ines_header() {
+        let raw = [b'N', b'E', b'S', 0x1a, 8, 0, 0x13, 0x40, 0, 1, 0, 0, 0, 0, 0, 0];
+        let header = RomHeader::parse(&raw).unwrap();
+        assert_eq!(header.format, HeaderFormat::INes);
+        assert_eq!(header.mapper, 0x41);
+        assert_eq!(header.mirroring, Mirroring::Vertical);
+        assert!(header.battery);
+        assert_eq!(header.prg_rom_size, 0x20000);
+        assert_eq!(header.chr_rom_size, 0);
+        assert_eq!(header.chr_ram_size, 0x2000);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+        assert_eq!(header.video_signal, VideoSignal::PAL);
+    }
+
+    #[test]
+    fn test_ines_header_with_garbage() {
+        let mut raw = [b'N', b'E', b'S', 0x1a, 2, 1, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw[7..16].copy_from_slice(b"DiskDude!");
+        let header = RomHeader::parse(&raw).unwrap();
+        assert_eq!(header.mapper, 1);
+        assert_eq!(header.console_type, ConsoleType::Nes);
+        assert_eq!(header.prg_ram_size, 0x2000);
+        assert_eq!(header.video_signal, VideoSignal::NTSC);
+    }
+
+    #[test]
+    fn test_nes20_header() {
+        let raw = [b'N', b'E', b'S', 0x1a, 0x02, 0x01, 0x41, 0x0a, 0x21, 0x01, 0x70, 0x07, 0x03, 0, 0, 0x01];
+        let header = RomHeader::parse(&raw).unwrap();
+        assert_eq!(header.format, HeaderFormat::Nes20);
+        assert_eq!(header.mapper, 0x104);
+        assert_eq!(header.submapper, 2);
+        assert_eq!(header.prg_rom_size, 0x102 * 0x4000);
+        assert_eq!(header.chr_rom_size, 0x2000);
+        assert_eq!(header.prg_ram_size, 0);
+        assert_eq!(header.prg_nvram_size, 0x2000);
+        assert_eq!(header.chr_ram_size, 0x2000);
+        assert_eq!(header.console_type, ConsoleType::Playchoice10);
+        assert_eq!(header.video_signal, VideoSignal::Dendy);
+        assert_eq!(header.expansion_device, 1);
+    }
+
+    #[test]
+    fn test_nes20_exponent_size() {
+        // 2^7 * 3 = 384 bytes
+        let raw = [b'N', b'E', b'S', 0x1a, 0b0001_1101, 0, 0, 0x08, 0, 0x0f, 0, 0, 0, 0, 0, 0];
+        let header = RomHeader::parse(&raw).unwrap();
+        assert_eq!(header.prg_rom_size, 384);
+    }
+}
@@ -1,81 +1,496 @@
-#[derive(Debug, PartialEq, Copy, Clone)]
+use std::path::Path;
+
+mod archive;
+mod axrom;
+mod cnrom;
+mod database;
+mod error;
+mod hash;
+mod header;
+mod mapper;
+mod mmc1;
+mod mmc3;
+mod nrom;
+mod patch;
+mod uxrom;
+
+pub use archive::extract_rom;
+pub use database::{GameDatabase, GameEntry};
+pub use error::CartridgeError;
+pub use header::{ConsoleType, HeaderFormat, RomHeader};
+pub use mapper::Mapper;
+pub use patch::apply_patch;
+use axrom::Axrom;
+use cnrom::Cnrom;
+use mmc1::Mmc1;
+use mmc3::Mmc3;
+use nrom::Nrom;
+use uxrom::Uxrom;
+
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
 pub enum Mirroring {
+    #[default]
     Invalid,
     Vertical,
     Horizontal,
     FourScreen,
+    // One-screen, lower bank
+    SingleScreenA,
+    // One-screen, upper bank
+    SingleScreenB,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
 pub enum VideoSignal {
     PAL,
-    NTSC
+    #[default]
+    NTSC,
+    // Runs on both NTSC and PAL consoles
+    MultiRegion,
+    Dendy,
+}
+
+// Console timing the emulator runs with
+// https://www.nesdev.org/wiki/Cycle_reference_chart
+#[derive(Debug, PartialEq, Copy, Clone, Default)]
+pub enum Region {
+    #[default]
+    NTSC,
+    PAL,
+    Dendy,
+}
+
+impl Region {
+    // Multi-region games run fine on NTSC
+    pub fn from_video_signal(video_signal: VideoSignal) -> Region {
+        match video_signal {
+            VideoSignal::PAL => Region::PAL,
+            VideoSignal::Dendy => Region::Dendy,
+            _ => Region::NTSC,
+        }
+    }
+
+    pub fn cpu_freq(&self) -> f64 {
+        match self {
+            Region::NTSC => 1_789_773.0,
+            Region::PAL => 1_662_607.0,
+            Region::Dendy => 1_773_448.0,
+        }
+    }
+
+    // PPU dots per CPU cycle as numerator and denominator, 3.2 on PAL
+    pub fn ppu_dots_per_cpu_cycle(&self) -> (usize, usize) {
+        match self {
+            Region::PAL => (16, 5),
+            _ => (3, 1),
+        }
+    }
+
+    pub fn scanlines_per_frame(&self) -> usize {
+        match self {
+            Region::NTSC => 262,
+            _ => 312,
+        }
+    }
+
+    pub fn frame_rate(&self) -> f64 {
+        // 341 dots per scanline, minus the skipped dot of every other NTSC frame
+        let dots = match self {
+            Region::NTSC => 341.0 * 262.0 - 0.5,
+            _ => 341.0 * 312.0,
+        };
+        let (num, den) = self.ppu_dots_per_cpu_cycle();
+        self.cpu_freq() * num as f64 / den as f64 / dots
+    }
 }
 
 pub struct Cartridge {
     pub prg_rom: Vec<u8>,
     pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    // Used instead of chr_rom when the board has no CHR-ROM
+    pub chr_ram: Vec<u8>,
+    // $6000-$7FFF
+    pub prg_ram: Vec<u8>,
+    // Nametables at PPU $2800-$2FFF on four-screen boards
+    pub vram: Vec<u8>,
+    prg_ram_dirty: bool,
+    pub header: RomHeader,
     pub screen_mirroring: Mirroring,
     pub loaded: bool,
-    pub video_signal: VideoSignal
+    mapper: Box<dyn Mapper>,
 }
 
-const NES_TAG: [u8; 4] = [b'N', b'E', b'S', 0x1a];
-
 impl Cartridge {
     pub fn new() -> Cartridge {
         Cartridge {
             prg_rom: Vec::from([]),
             chr_rom: Vec::from([]),
-            mapper: 0,
+            chr_ram: Vec::from([]),
+            prg_ram: Vec::from([]),
+            vram: Vec::from([]),
+            prg_ram_dirty: false,
+            header: RomHeader::default(),
             screen_mirroring: Mirroring::Invalid,
             loaded: false,
-            video_signal: VideoSignal::NTSC,
+            mapper: Box::new(Nrom::new(0)),
         }
     }
 
-    pub fn load(raw: &Vec<u8>) -> Result<Cartridge, &str> {
-        if &raw[0..4] != NES_TAG {
-            return Err("Header did not NES^Z");
-        }
+    pub fn load(raw: &[u8]) -> Result<Cartridge, CartridgeError> {
+        Self::load_with_log(raw, |_| {})
+    }
 
-        let has_trainer = raw[6] & 0b0100 > 0;
-        let prg_rom_size = 16 * 1024 * (raw[4] as usize);
-        let chr_rom_size = 8 * 1024 * (raw[5] as usize);
-        let prg_rom_start = 0x10 + if has_trainer { 512 } else { 0 };
-        let chr_rom_start = prg_rom_start + prg_rom_size;
-        let prg_rom_range = prg_rom_start..(prg_rom_start + prg_rom_size);
-        let chr_rom_range = chr_rom_start..(chr_rom_start + chr_rom_size);
+    // Same as load, but passes diagnostics to the given logger
+    pub fn load_with_log(raw: &[u8], log: impl FnMut(&str)) -> Result<Cartridge, CartridgeError> {
+        Self::load_with_db(raw, &GameDatabase::new(), log)
+    }
 
-        let mapper = ((raw[6] & 0b1111_0000) >> 4) | (raw[7] & 0b1111_0000);
+    // Header fields are overridden by the database if the ROM is found in it
+    pub fn load_with_db(
+        raw: &[u8],
+        db: &GameDatabase,
+        mut log: impl FnMut(&str),
+    ) -> Result<Cartridge, CartridgeError> {
+        // Zip and gzip are unpacked transparently
+        let raw = &extract_rom(raw, None)?[..];
+        let mut header = RomHeader::parse(raw)?;
 
-        let screen_mirroring = match raw[6] & 0b0000_1001 {
-            0b0000_0000 => Mirroring::Horizontal,
-            0b0000_0001 => Mirroring::Vertical,
-            0b0000_1000 => Mirroring::FourScreen,
-            0b0000_1001 => Mirroring::FourScreen,
-            _ => {
-                return Err("Invalid screen mirroring type");
-            }
-        };
+        // NES 2.0 exponent sizes go up to usize::MAX, which is never in the file
+        let prg_rom_start = header.prg_rom_start();
+        let chr_rom_start = header.chr_rom_start();
+        let prg_rom_range = prg_rom_start..prg_rom_start.saturating_add(header.prg_rom_size);
+        let chr_rom_range = chr_rom_start..chr_rom_start.saturating_add(header.chr_rom_size);
+        if raw.len() < prg_rom_range.end {
+            return Err(CartridgeError::TruncatedPrg {
+                expected: header.prg_rom_size,
+                actual: raw.len().saturating_sub(prg_rom_start),
+            });
+        }
+        if raw.len() < chr_rom_range.end {
+            return Err(CartridgeError::TruncatedChr {
+                expected: header.chr_rom_size,
+                actual: raw.len() - chr_rom_start,
+            });
+        }
 
-        let video_signal = match raw[9] & 1 {
-            1 => VideoSignal::PAL,
-            _ => VideoSignal::NTSC
+        if let Some(entry) = db.find(&raw[prg_rom_start..chr_rom_range.end]) {
+            entry.apply(&mut header, &mut log);
+        }
+
+        // CHR-RAM is only used when the board has no CHR-ROM
+        let chr_ram_size = match header.chr_rom_size {
+            0 => (header.chr_ram_size + header.chr_nvram_size).max(0x2000),
+            _ => 0,
         };
+        let prg_ram_size = header.prg_ram_size + header.prg_nvram_size;
+        let vram_size = match header.mirroring {
+            Mirroring::FourScreen => 0x800,
+            _ => 0,
+        };
+        let mapper = new_mapper(&header, header.chr_rom_size + chr_ram_size)?;
 
-        eprintln!("prg_rom: {:?} 0x{:04X}", prg_rom_range, prg_rom_size);
-        eprintln!("chr_rom: {:?} 0x{:04X}", chr_rom_range, chr_rom_size);
-        eprintln!("video_signal: {:?}", video_signal);
+        log(&format!("prg_rom: {:?} 0x{:04X}", prg_rom_range, header.prg_rom_size));
+        log(&format!("chr_rom: {:?} 0x{:04X}", chr_rom_range, header.chr_rom_size));
+        log(&format!("chr_ram: 0x{:04X}", chr_ram_size));
+        log(&format!("prg_ram: 0x{:04X}", prg_ram_size));
+        log(&format!("battery: {}", header.battery));
+        log(&format!("format: {:?}", header.format));
+        log(&format!("video_signal: {:?}", header.video_signal));
+        log(&format!("mapper: {}.{}", header.mapper, header.submapper));
 
         Ok(Cartridge {
             prg_rom: raw[prg_rom_range].to_vec(),
             chr_rom: raw[chr_rom_range].to_vec(),
-            mapper,
-            screen_mirroring,
-            video_signal,
+            chr_ram: vec![0; chr_ram_size],
+            prg_ram: vec![0; prg_ram_size],
+            vram: vec![0; vram_size],
+            prg_ram_dirty: false,
+            screen_mirroring: header.mirroring,
+            header,
             loaded: true,
+            mapper,
         })
     }
+
+    // CPU $8000-$FFFF
+    pub fn read_prg(&self, address: u16) -> u8 {
+        self.prg_rom[self.mapper.prg_rom_offset(address)]
+    }
+
+    pub fn write_prg(&mut self, address: u16, data: u8) {
+        let data = if self.mapper.has_bus_conflicts() {
+            data & self.read_prg(address)
+        } else {
+            data
+        };
+        self.mapper.write_register(address, data);
+    }
+
+    // CPU $6000-$7FFF
+    pub fn read_prg_ram(&self, address: u16) -> u8 {
+        if self.prg_ram.is_empty() || !self.mapper.prg_ram_readable() {
+            return 0;
+        }
+        let offset = (address - 0x6000) as usize % self.prg_ram.len();
+        self.prg_ram[offset]
+    }
+
+    pub fn write_prg_ram(&mut self, address: u16, data: u8) {
+        if self.prg_ram.is_empty() || !self.mapper.prg_ram_writable() {
+            return;
+        }
+        let offset = (address - 0x6000) as usize % self.prg_ram.len();
+        if self.prg_ram[offset] != data {
+            self.prg_ram[offset] = data;
+            self.prg_ram_dirty = true;
+        }
+    }
+
+    // True if PRG-RAM has been modified since the last save
+    pub fn is_battery_ram_dirty(&self) -> bool {
+        self.header.battery && self.prg_ram_dirty
+    }
+
+    pub fn load_battery_ram(&mut self, path: &Path) -> std::io::Result<()> {
+        let data = std::fs::read(path)?;
+        let len = data.len().min(self.prg_ram.len());
+        self.prg_ram[..len].copy_from_slice(&data[..len]);
+        self.prg_ram_dirty = false;
+        Ok(())
+    }
+
+    pub fn save_battery_ram(&mut self, path: &Path) -> std::io::Result<()> {
+        std::fs::write(path, &self.prg_ram)?;
+        self.prg_ram_dirty = false;
+        Ok(())
+    }
+
+    fn chr(&self) -> &[u8] {
+        if self.chr_ram.is_empty() {
+            &self.chr_rom
+        } else {
+            &self.chr_ram
+        }
+    }
+
+    // PPU $0000-$1FFF
+    pub fn read_chr(&self, address: u16) -> u8 {
+        self.chr()[self.mapper.chr_offset(address)]
+    }
+
+    pub fn write_chr(&mut self, address: u16, data: u8) {
+        // Writes to CHR-ROM are ignored
+        if !self.chr_ram.is_empty() {
+            let offset = self.mapper.chr_offset(address);
+            self.chr_ram[offset] = data;
+        }
+    }
+
+    // PPU $2800-$2FFF on four-screen boards
+    pub fn read_vram(&self, address: u16) -> u8 {
+        self.vram[address as usize & 0x7ff]
+    }
+
+    pub fn write_vram(&mut self, address: u16, data: u8) {
+        self.vram[address as usize & 0x7ff] = data;
+    }
+
+    pub fn on_a12_rise(&mut self) {
+        self.mapper.on_a12_rise();
+    }
+
+    pub fn irq(&self) -> bool {
+        self.mapper.irq()
+    }
+
+    pub fn reset(&mut self) {
+        self.mapper.reset();
+    }
+
+    // Mapper registers and volatile RAM start over, battery-backed PRG-RAM is kept
+    pub fn power_cycle(&mut self) {
+        if !self.loaded {
+            return;
+        }
+        if let Ok(mapper) = new_mapper(&self.header, self.chr_rom.len() + self.chr_ram.len()) {
+            self.mapper = mapper;
+        }
+        if !self.header.battery {
+            self.prg_ram.fill(0);
+        }
+        self.chr_ram.fill(0);
+        self.vram.fill(0);
+    }
+
+    // Mappers like MMC1 can switch mirroring at runtime
+    pub fn mirroring(&self) -> Mirroring {
+        self.mapper.mirroring().unwrap_or(self.screen_mirroring)
+    }
+}
+
+fn new_mapper(header: &RomHeader, chr_size: usize) -> Result<Box<dyn Mapper>, CartridgeError> {
+    let prg_rom_size = header.prg_rom_size;
+    // These switch mirroring by themselves and have no extra VRAM for four screens
+    if header.mirroring == Mirroring::FourScreen && matches!(header.mapper, 1 | 7) {
+        return Err(CartridgeError::InvalidMirroring);
+    }
+    // NES 2.0 submapper 1 is without bus conflicts and 2 is with them
+    let mapper: Box<dyn Mapper> = match header.mapper {
+        0 => Box::new(Nrom::new(prg_rom_size)),
+        1 => Box::new(Mmc1::new(prg_rom_size, chr_size)),
+        2 => Box::new(Uxrom::new(prg_rom_size, header.submapper != 1)),
+        3 => Box::new(Cnrom::new(prg_rom_size, chr_size, header.submapper != 1)),
+        4 => Box::new(Mmc3::new(prg_rom_size, chr_size, header.mirroring)),
+        7 => Box::new(Axrom::new(prg_rom_size, header.submapper == 2)),
+        _ => {
+            return Err(CartridgeError::UnsupportedMapper(header.mapper));
+        }
+    };
+    Ok(mapper)
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn ines(prg_banks: u8, chr_banks: u8, flags6: u8) -> Vec<u8> {
+        let mut raw = vec![b'N', b'E', b'S', 0x1a, prg_banks, chr_banks, flags6, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.resize(16 + prg_banks as usize * 0x4000 + chr_banks as usize * 0x2000, 0);
+        raw
+    }
+
+    #[test]
+    fn test_load_errors() {
+        assert_eq!(Cartridge::load(b"NES").err(), Some(CartridgeError::BadMagic));
+        assert_eq!(Cartridge::load(&[0; 16]).err(), Some(CartridgeError::BadMagic));
+
+        let raw = ines(2, 1, 0);
+        assert_eq!(
+            Cartridge::load(&raw[..0x4010]).err(),
+            Some(CartridgeError::TruncatedPrg { expected: 0x8000, actual: 0x4000 })
+        );
+        assert_eq!(
+            Cartridge::load(&raw[..0x8110]).err(),
+            Some(CartridgeError::TruncatedChr { expected: 0x2000, actual: 0x100 })
+        );
+
+        assert_eq!(Cartridge::load(&ines(1, 1, 0xf0)).err(), Some(CartridgeError::UnsupportedMapper(15)));
+        assert_eq!(Cartridge::load(&ines(1, 1, 0x18)).err(), Some(CartridgeError::InvalidMirroring));
+
+        // NES 2.0 exponent sizes of 2^63 * 3 bytes
+        let mut raw = ines(1, 1, 0);
+        raw[7] = 0x08;
+        raw[4] = 0xff;
+        raw[9] = 0x0f;
+        assert_eq!(
+            Cartridge::load(&raw).err(),
+            Some(CartridgeError::TruncatedPrg { expected: usize::MAX, actual: 0x6000 })
+        );
+        raw[4] = 1;
+        raw[5] = 0xff;
+        raw[9] = 0xf0;
+        assert_eq!(
+            Cartridge::load(&raw).err(),
+            Some(CartridgeError::TruncatedChr { expected: usize::MAX, actual: 0x2000 })
+        );
+    }
+
+    #[test]
+    fn test_load_with_log() {
+        let mut lines = vec![];
+        Cartridge::load_with_log(&ines(1, 1, 0), |line| lines.push(line.to_string())).unwrap();
+        assert!(lines.contains(&"mapper: 0.0".to_string()));
+    }
+
+    #[test]
+    fn test_load_with_db() {
+        let raw = ines(1, 1, 0x10);
+        let xml = format!(
+            r#"<game><rom size="24576" crc32="{:08X}"/><pcb mapper="0" mirroring="V"/></game>"#,
+            hash::crc32(&raw[16..])
+        );
+        let db = GameDatabase::parse(&xml);
+
+        let mut lines = vec![];
+        let cartridge = Cartridge::load_with_db(&raw, &db, |line| lines.push(line.to_string())).unwrap();
+        assert_eq!(cartridge.header.mapper, 0);
+        assert_eq!(cartridge.mirroring(), Mirroring::Vertical);
+        assert!(lines.contains(&"database: mapper 1 -> 0".to_string()));
+    }
+
+    #[test]
+    fn test_region_frame_rate() {
+        assert!((Region::NTSC.frame_rate() - 60.0988).abs() < 0.001);
+        assert!((Region::PAL.frame_rate() - 50.0070).abs() < 0.001);
+        assert!((Region::Dendy.frame_rate() - 50.0070).abs() < 0.01);
+        assert_eq!(Region::from_video_signal(VideoSignal::MultiRegion), Region::NTSC);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let mut cartridge = Cartridge::load(&ines(1, 0, 0)).unwrap();
+        assert_eq!(cartridge.chr_ram.len(), 0x2000);
+
+        cartridge.write_chr(0x1fff, 0x66);
+        assert_eq!(cartridge.read_chr(0x1fff), 0x66);
+
+        // CHR-ROM is read only
+        let mut cartridge = Cartridge::load(&ines(1, 1, 0)).unwrap();
+        cartridge.write_chr(0x0000, 0x66);
+        assert_eq!(cartridge.read_chr(0x0000), 0x00);
+    }
+
+    #[test]
+    fn test_four_screen_vram() {
+        assert!(Cartridge::load(&ines(1, 1, 0)).unwrap().vram.is_empty());
+
+        let mut cartridge = Cartridge::load(&ines(1, 1, 0x08)).unwrap();
+        assert_eq!(cartridge.mirroring(), Mirroring::FourScreen);
+        assert_eq!(cartridge.vram.len(), 0x800);
+        cartridge.write_vram(0x2c05, 0x66);
+        assert_eq!(cartridge.read_vram(0x0405), 0x66);
+
+        cartridge.power_cycle();
+        assert_eq!(cartridge.read_vram(0x0405), 0x00);
+    }
+
+    #[test]
+    fn test_battery_ram() {
+        let mut cartridge = Cartridge::load(&ines(1, 1, 0b0010)).unwrap();
+        assert!(cartridge.header.battery);
+        assert!(!cartridge.is_battery_ram_dirty());
+
+        cartridge.write_prg_ram(0x6000, 0x66);
+        cartridge.write_prg_ram(0x7fff, 0x77);
+        assert_eq!(cartridge.read_prg_ram(0x6000), 0x66);
+        assert!(cartridge.is_battery_ram_dirty());
+
+        let path = std::env::temp_dir().join(format!("cartridge_test_{}.sav", std::process::id()));
+        cartridge.save_battery_ram(&path).unwrap();
+        assert!(!cartridge.is_battery_ram_dirty());
+
+        let mut cartridge = Cartridge::load(&ines(1, 1, 0b0010)).unwrap();
+        cartridge.load_battery_ram(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(cartridge.read_prg_ram(0x6000), 0x66);
+        assert_eq!(cartridge.read_prg_ram(0x7fff), 0x77);
+    }
+
+    #[test]
+    fn test_bus_conflicts() {
+        // UNROM with 8 banks, each bank filled with its own number
+        let mut raw = ines(8, 0, 0x20);
+        for (i, byte) in raw[16..].iter_mut().enumerate() {
+            *byte = (i / 0x4000) as u8;
+        }
+        let mut cartridge = Cartridge::load(&raw).unwrap();
+
+        // The fixed bank holds 7, so every bit of the written value survives
+        cartridge.write_prg(0xc000, 5);
+        assert_eq!(cartridge.read_prg(0x8000), 5);
+
+        // Bank 5 holds 5, so writing 3 selects 5 & 3 = 1
+        cartridge.write_prg(0x8000, 3);
+        assert_eq!(cartridge.read_prg(0x8000), 1);
+    }
 }
@@ -0,0 +1,63 @@
+use crate::Mirroring;
+
+// https://www.nesdev.org/wiki/Mapper
+// A mapper sits between the CPU/PPU buses and the cartridge memory.
+// It translates bus addresses into offsets of PRG-ROM and CHR memory,
+// and latches writes to its registers.
+pub trait Mapper {
+    // CPU $8000-$FFFF -> offset of PRG-ROM
+    fn prg_rom_offset(&self, address: u16) -> usize;
+
+    // PPU $0000-$1FFF -> offset of CHR memory
+    fn chr_offset(&self, address: u16) -> usize;
+
+    // CPU writes to $8000-$FFFF
+    fn write_register(&mut self, address: u16, data: u8);
+
+    // PRG-RAM at $6000-$7FFF can be disabled or write protected by some mappers
+    fn prg_ram_readable(&self) -> bool {
+        true
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        true
+    }
+
+    // None means the mirroring is hard-wired and given by the header
+    fn mirroring(&self) -> Option<Mirroring> {
+        None
+    }
+
+    // Rising edge of PPU A12, which MMC3 uses to count scanlines
+    fn on_a12_rise(&mut self) {}
+
+    // Boards without a write-enable on PRG-ROM see the ROM and the CPU
+    // drive the data bus together, so the written value is ANDed with the ROM
+    fn has_bus_conflicts(&self) -> bool {
+        false
+    }
+
+    // Level of the IRQ line driven by the mapper
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // The reset button. Most boards do not see it, so their registers survive a soft reset.
+    // Power cycling creates a new mapper instead.
+    fn reset(&mut self) {}
+}
+
+pub const PRG_BANK_8K: usize = 0x2000;
+pub const PRG_BANK_16K: usize = 0x4000;
+pub const PRG_BANK_32K: usize = 0x8000;
+pub const CHR_BANK_1K: usize = 0x0400;
+pub const CHR_BANK_4K: usize = 0x1000;
+pub const CHR_BANK_8K: usize = 0x2000;
+
+// Returns the bank offset wrapped around the size of the memory
+pub fn bank_offset(bank: usize, bank_size: usize, memory_size: usize) -> usize {
+    if memory_size == 0 {
+        return 0;
+    }
+    (bank * bank_size) % memory_size
+}
@@ -0,0 +1,201 @@
+use crate::mapper::{bank_offset, Mapper, CHR_BANK_4K, PRG_BANK_16K, PRG_BANK_32K};
+use crate::Mirroring;
+
+// https://www.nesdev.org/wiki/MMC1
+pub struct Mmc1 {
+    prg_rom_size: usize,
+    chr_size: usize,
+    // Serial port
+    shift_register: u8,
+    shift_count: u8,
+    // Internal registers
+    control: u8,
+    chr_bank_0: u8,
+    chr_bank_1: u8,
+    prg_bank: u8,
+}
+
+impl Mmc1 {
+    pub fn new(prg_rom_size: usize, chr_size: usize) -> Self {
+        Mmc1 {
+            prg_rom_size,
+            chr_size,
+            shift_register: 0,
+            shift_count: 0,
+            // PRG ROM bank mode 3 at power on
+            control: 0b0_1100,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn reset_shift_register(&mut self) {
+        self.shift_register = 0;
+        self.shift_count = 0;
+    }
+
+    // SUROM and SXROM use bit 4 of the CHR bank to select a 256KB PRG-ROM half
+    fn prg_outer_bank(&self) -> usize {
+        if self.prg_rom_size > 0x40000 {
+            (self.chr_bank_0 & 0b1_0000) as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let address = (address - 0x8000) as usize;
+        let bank = (self.prg_bank & 0b1111) as usize | self.prg_outer_bank();
+        let last_bank =
+            ((self.prg_rom_size / PRG_BANK_16K).saturating_sub(1) & 0b1111) | self.prg_outer_bank();
+
+        let offset = match ((self.control >> 2) & 0b11, address < 0x4000) {
+            // Switch 32KB at $8000, ignoring low bit of bank number
+            (0 | 1, _) => bank_offset(bank >> 1, PRG_BANK_32K, self.prg_rom_size) + address,
+            // Fix first bank at $8000 and switch 16KB bank at $C000
+            (2, true) => bank_offset(self.prg_outer_bank(), PRG_BANK_16K, self.prg_rom_size) + address,
+            (2, false) => bank_offset(bank, PRG_BANK_16K, self.prg_rom_size) + address - 0x4000,
+            // Fix last bank at $C000 and switch 16KB bank at $8000
+            (_, true) => bank_offset(bank, PRG_BANK_16K, self.prg_rom_size) + address,
+            (_, false) => bank_offset(last_bank, PRG_BANK_16K, self.prg_rom_size) + address - 0x4000,
+        };
+        offset % self.prg_rom_size.max(1)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let address = address as usize;
+        let offset = if self.control & 0b1_0000 == 0 {
+            // Switch 8KB at a time, ignoring low bit of bank number
+            bank_offset((self.chr_bank_0 & 0b1_1110) as usize, CHR_BANK_4K, self.chr_size) + address
+        } else if address < 0x1000 {
+            bank_offset(self.chr_bank_0 as usize, CHR_BANK_4K, self.chr_size) + address
+        } else {
+            bank_offset(self.chr_bank_1 as usize, CHR_BANK_4K, self.chr_size) + address - 0x1000
+        };
+        offset % self.chr_size.max(1)
+    }
+
+    fn write_register(&mut self, address: u16, data: u8) {
+        // Writing a value with bit 7 set clears the shift register
+        if data & 0b1000_0000 > 0 {
+            self.reset_shift_register();
+            self.control |= 0b0_1100;
+            return;
+        }
+
+        self.shift_register |= (data & 1) << self.shift_count;
+        self.shift_count += 1;
+        if self.shift_count < 5 {
+            return;
+        }
+
+        // The fifth write copies the value into the register selected by bits 14 and 13
+        let value = self.shift_register;
+        match address {
+            0x8000..=0x9fff => self.control = value,
+            0xa000..=0xbfff => self.chr_bank_0 = value,
+            0xc000..=0xdfff => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+        self.reset_shift_register();
+    }
+
+    fn prg_ram_readable(&self) -> bool {
+        // Bit 4 of the PRG bank disables PRG-RAM on MMC1B and later
+        self.prg_bank & 0b1_0000 == 0
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_readable()
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        Some(match self.control & 0b11 {
+            0 => Mirroring::SingleScreenA,
+            1 => Mirroring::SingleScreenB,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn write_serial(mmc1: &mut Mmc1, address: u16, value: u8) {
+        for i in 0..5 {
+            mmc1.write_register(address, (value >> i) & 1);
+        }
+    }
+
+    #[test]
+    fn test_mmc1_power_on_fixes_last_bank() {
+        let mmc1 = Mmc1::new(0x20000, 0x2000);
+        assert_eq!(mmc1.prg_rom_offset(0x8000), 0);
+        assert_eq!(mmc1.prg_rom_offset(0xc000), 0x1c000);
+        assert_eq!(mmc1.prg_rom_offset(0xffff), 0x1ffff);
+    }
+
+    #[test]
+    fn test_mmc1_prg_bank_switch() {
+        let mut mmc1 = Mmc1::new(0x20000, 0x2000);
+        write_serial(&mut mmc1, 0xe000, 3);
+        assert_eq!(mmc1.prg_rom_offset(0x8000), 0xc000);
+        assert_eq!(mmc1.prg_rom_offset(0xc000), 0x1c000);
+
+        // Fix first bank
+        write_serial(&mut mmc1, 0x8000, 0b0_1000);
+        assert_eq!(mmc1.prg_rom_offset(0x8000), 0);
+        assert_eq!(mmc1.prg_rom_offset(0xc000), 0xc000);
+
+        // 32KB mode ignores low bit
+        write_serial(&mut mmc1, 0x8000, 0b0_0000);
+        assert_eq!(mmc1.prg_rom_offset(0x8000), 0x8000);
+        assert_eq!(mmc1.prg_rom_offset(0xc000), 0xc000);
+    }
+
+    #[test]
+    fn test_mmc1_chr_bank_switch() {
+        let mut mmc1 = Mmc1::new(0x20000, 0x20000);
+        // 4KB mode
+        write_serial(&mut mmc1, 0x8000, 0b1_1100);
+        write_serial(&mut mmc1, 0xa000, 5);
+        write_serial(&mut mmc1, 0xc000, 2);
+        assert_eq!(mmc1.chr_offset(0x0010), 0x5010);
+        assert_eq!(mmc1.chr_offset(0x1010), 0x2010);
+
+        // 8KB mode
+        write_serial(&mut mmc1, 0x8000, 0b0_1100);
+        assert_eq!(mmc1.chr_offset(0x0010), 0x4010);
+        assert_eq!(mmc1.chr_offset(0x1010), 0x5010);
+    }
+
+    #[test]
+    fn test_mmc1_reset_and_mirroring() {
+        let mut mmc1 = Mmc1::new(0x20000, 0x2000);
+        write_serial(&mut mmc1, 0x8000, 0b0_1110);
+        assert_eq!(mmc1.mirroring(), Some(Mirroring::Vertical));
+
+        // Bit 7 discards a partially written value
+        mmc1.write_register(0x8000, 1);
+        mmc1.write_register(0x8000, 0x80);
+        write_serial(&mut mmc1, 0x8000, 0b0_0011);
+        assert_eq!(mmc1.mirroring(), Some(Mirroring::Horizontal));
+
+        write_serial(&mut mmc1, 0x8000, 0b0_0001);
+        assert_eq!(mmc1.mirroring(), Some(Mirroring::SingleScreenB));
+    }
+
+    #[test]
+    fn test_mmc1_prg_ram_disable() {
+        let mut mmc1 = Mmc1::new(0x20000, 0x2000);
+        assert!(mmc1.prg_ram_readable());
+        write_serial(&mut mmc1, 0xe000, 0b1_0000);
+        assert!(!mmc1.prg_ram_readable());
+        assert!(!mmc1.prg_ram_writable());
+    }
+}
@@ -0,0 +1,220 @@
+use crate::mapper::{bank_offset, Mapper, CHR_BANK_1K, PRG_BANK_8K};
+use crate::Mirroring;
+
+// https://www.nesdev.org/wiki/MMC3
+pub struct Mmc3 {
+    prg_rom_size: usize,
+    chr_size: usize,
+    hardwired_mirroring: Mirroring,
+    // $8000
+    bank_select: u8,
+    // R0-R7 set by $8001
+    bank_registers: [u8; 8],
+    // $A000
+    mirroring: Mirroring,
+    // $A001
+    prg_ram_protect: u8,
+    // Scanline counter
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+}
+
+impl Mmc3 {
+    pub fn new(prg_rom_size: usize, chr_size: usize, mirroring: Mirroring) -> Self {
+        Mmc3 {
+            prg_rom_size,
+            chr_size,
+            hardwired_mirroring: mirroring,
+            bank_select: 0,
+            bank_registers: [0, 2, 4, 5, 6, 7, 0, 1],
+            mirroring,
+            prg_ram_protect: 0b1000_0000,
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+        }
+    }
+
+    fn prg_bank_count(&self) -> usize {
+        self.prg_rom_size / PRG_BANK_8K
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let second_last = self.prg_bank_count().saturating_sub(2);
+        let last = self.prg_bank_count().saturating_sub(1);
+        let r6 = (self.bank_registers[6] & 0b0011_1111) as usize;
+        let r7 = (self.bank_registers[7] & 0b0011_1111) as usize;
+        let prg_mode = self.bank_select & 0b0100_0000 > 0;
+
+        let bank = match (address, prg_mode) {
+            (0x8000..=0x9fff, false) => r6,
+            (0x8000..=0x9fff, true) => second_last,
+            (0xa000..=0xbfff, _) => r7,
+            (0xc000..=0xdfff, false) => second_last,
+            (0xc000..=0xdfff, true) => r6,
+            _ => last,
+        };
+        bank_offset(bank, PRG_BANK_8K, self.prg_rom_size) + (address as usize & 0x1fff)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        // CHR A12 inversion swaps the 2KB banks and the 1KB banks
+        let address = if self.bank_select & 0b1000_0000 > 0 {
+            address ^ 0x1000
+        } else {
+            address
+        };
+        let r = &self.bank_registers;
+        let bank = match address {
+            // R0 and R1 select 2KB banks, ignoring the low bit
+            0x0000..=0x03ff => r[0] & 0xfe,
+            0x0400..=0x07ff => r[0] | 0x01,
+            0x0800..=0x0bff => r[1] & 0xfe,
+            0x0c00..=0x0fff => r[1] | 0x01,
+            0x1000..=0x13ff => r[2],
+            0x1400..=0x17ff => r[3],
+            0x1800..=0x1bff => r[4],
+            _ => r[5],
+        } as usize;
+        bank_offset(bank, CHR_BANK_1K, self.chr_size) + (address as usize & 0x3ff)
+    }
+
+    fn write_register(&mut self, address: u16, data: u8) {
+        let even = address & 1 == 0;
+        match (address, even) {
+            (0x8000..=0x9fff, true) => self.bank_select = data,
+            (0x8000..=0x9fff, false) => {
+                let index = (self.bank_select & 0b111) as usize;
+                self.bank_registers[index] = data;
+            }
+            (0xa000..=0xbfff, true) => {
+                self.mirroring = match data & 1 {
+                    0 => Mirroring::Vertical,
+                    _ => Mirroring::Horizontal,
+                };
+            }
+            (0xa000..=0xbfff, false) => self.prg_ram_protect = data,
+            (0xc000..=0xdfff, true) => self.irq_latch = data,
+            (0xc000..=0xdfff, false) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (_, true) => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            (_, false) => self.irq_enabled = true,
+        }
+    }
+
+    fn prg_ram_readable(&self) -> bool {
+        self.prg_ram_protect & 0b1000_0000 > 0
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        // Bit 6 denies writes while the chip is still enabled
+        self.prg_ram_protect & 0b1100_0000 == 0b1000_0000
+    }
+
+    fn mirroring(&self) -> Option<Mirroring> {
+        match self.hardwired_mirroring {
+            Mirroring::FourScreen => None,
+            _ => Some(self.mirroring),
+        }
+    }
+
+    fn on_a12_rise(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_mmc3_prg_banks() {
+        let mut mmc3 = Mmc3::new(0x40000, 0x40000, Mirroring::Vertical);
+        mmc3.write_register(0x8000, 6);
+        mmc3.write_register(0x8001, 3);
+        mmc3.write_register(0x8000, 7);
+        mmc3.write_register(0x8001, 4);
+        assert_eq!(mmc3.prg_rom_offset(0x8000), 0x6000);
+        assert_eq!(mmc3.prg_rom_offset(0xa000), 0x8000);
+        assert_eq!(mmc3.prg_rom_offset(0xc000), 0x3c000);
+        assert_eq!(mmc3.prg_rom_offset(0xe001), 0x3e001);
+
+        // Swap $8000 and $C000
+        mmc3.write_register(0x8000, 0b0100_0000);
+        assert_eq!(mmc3.prg_rom_offset(0x8000), 0x3c000);
+        assert_eq!(mmc3.prg_rom_offset(0xc000), 0x6000);
+    }
+
+    #[test]
+    fn test_mmc3_chr_banks() {
+        let mut mmc3 = Mmc3::new(0x40000, 0x40000, Mirroring::Vertical);
+        mmc3.write_register(0x8000, 0);
+        mmc3.write_register(0x8001, 9);
+        mmc3.write_register(0x8000, 2);
+        mmc3.write_register(0x8001, 0x20);
+        assert_eq!(mmc3.chr_offset(0x0000), 0x2000);
+        assert_eq!(mmc3.chr_offset(0x0401), 0x2401);
+        assert_eq!(mmc3.chr_offset(0x1000), 0x8000);
+
+        // CHR A12 inversion
+        mmc3.write_register(0x8000, 0b1000_0000);
+        assert_eq!(mmc3.chr_offset(0x0000), 0x8000);
+        assert_eq!(mmc3.chr_offset(0x1401), 0x2401);
+    }
+
+    #[test]
+    fn test_mmc3_mirroring() {
+        let mut mmc3 = Mmc3::new(0x40000, 0x40000, Mirroring::Vertical);
+        mmc3.write_register(0xa000, 1);
+        assert_eq!(mmc3.mirroring(), Some(Mirroring::Horizontal));
+
+        let mut mmc3 = Mmc3::new(0x40000, 0x40000, Mirroring::FourScreen);
+        mmc3.write_register(0xa000, 1);
+        assert_eq!(mmc3.mirroring(), None);
+    }
+
+    #[test]
+    fn test_mmc3_scanline_irq() {
+        let mut mmc3 = Mmc3::new(0x40000, 0x40000, Mirroring::Vertical);
+        mmc3.write_register(0xc000, 2);
+        mmc3.write_register(0xc001, 0);
+        mmc3.write_register(0xe001, 0);
+
+        // Reload, then count down 2 -> 1 -> 0
+        mmc3.on_a12_rise();
+        assert!(!mmc3.irq());
+        mmc3.on_a12_rise();
+        assert!(!mmc3.irq());
+        mmc3.on_a12_rise();
+        assert!(mmc3.irq());
+
+        // Acknowledge
+        mmc3.write_register(0xe000, 0);
+        assert!(!mmc3.irq());
+    }
+}
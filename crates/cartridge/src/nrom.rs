@@ -0,0 +1,31 @@
+use crate::mapper::Mapper;
+
+// https://www.nesdev.org/wiki/NROM
+pub struct Nrom {
+    prg_rom_size: usize,
+}
+
+impl Nrom {
+    pub fn new(prg_rom_size: usize) -> Self {
+        Nrom { prg_rom_size }
+    }
+}
+
+impl Mapper for Nrom {
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let mut offset = (address - 0x8000) as usize;
+        // NROM-128 mirrors its 16KB to $C000-$FFFF
+        if self.prg_rom_size == 0x4000 && offset >= 0x4000 {
+            offset -= 0x4000;
+        }
+        offset
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        address as usize
+    }
+
+    fn write_register(&mut self, _address: u16, _data: u8) {
+        // No registers
+    }
+}
@@ -0,0 +1,376 @@
+use crate::hash::crc32;
+use crate::CartridgeError;
+
+// Far beyond any NES image, the target size in a patch is not trusted
+const MAX_TARGET_SIZE: usize = 0x400_0000;
+
+// Applies an IPS, UPS or BPS patch to the raw image, detected by its magic.
+// UPS and BPS are checked against the CRC32 of the source, target and patch.
+pub fn apply_patch(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    if patch.starts_with(b"PATCH") {
+        apply_ips(rom, patch)
+    } else if patch.starts_with(b"UPS1") {
+        apply_ups(rom, patch)
+    } else if patch.starts_with(b"BPS1") {
+        apply_bps(rom, patch)
+    } else {
+        Err(CartridgeError::BadPatch("Unknown patch format"))
+    }
+}
+
+// https://zerosoft.zophar.net/ips.php
+fn apply_ips(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    let mut output = rom.to_vec();
+    let mut reader = Reader::new(patch, 5);
+
+    loop {
+        if reader.bytes(3)? == b"EOF" {
+            break;
+        }
+        reader.offset -= 3;
+        let offset = reader.be(3)?;
+        let size = reader.be(2)?;
+        let (size, data) = match size {
+            // RLE record
+            0 => {
+                let size = reader.be(2)?;
+                (size, vec![reader.byte()?; size])
+            }
+            _ => (size, reader.bytes(size)?.to_vec()),
+        };
+        if output.len() < offset + size {
+            output.resize(offset + size, 0);
+        }
+        output[offset..offset + size].copy_from_slice(&data);
+    }
+
+    // Optional truncation extension
+    if reader.remaining() == 3 {
+        output.truncate(reader.be(3)?);
+    } else if reader.remaining() != 0 {
+        return Err(CartridgeError::BadPatch("Truncated IPS patch"));
+    }
+    Ok(output)
+}
+
+// https://www.romhacking.net/documents/392/
+fn apply_ups(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    let target_crc = verify_footer(rom, patch)?;
+    let mut reader = Reader::new(&patch[..patch.len() - 12], 4);
+    let out_of_range = CartridgeError::BadPatch("UPS offset is out of range");
+    let source_size = reader.varint()?;
+    let target_size = target_size(reader.varint()?)?;
+    if source_size != rom.len() {
+        return Err(CartridgeError::BadPatch("Source size mismatch"));
+    }
+
+    // Bytes past the source are XORed with 0, and the ones past the target are dropped
+    let mut output = rom.to_vec();
+    output.truncate(target_size);
+    let mut offset: usize = 0;
+    while reader.remaining() > 0 {
+        offset = offset.checked_add(reader.varint()?).ok_or(out_of_range.clone())?;
+        // XOR until a zero byte, which is XORed too
+        loop {
+            let x = reader.byte()?;
+            if offset < target_size {
+                if output.len() <= offset {
+                    output.resize(offset + 1, 0);
+                }
+                output[offset] ^= x;
+            }
+            offset = offset.checked_add(1).ok_or(out_of_range.clone())?;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+    output.resize(target_size, 0);
+
+    verify_target(&output, target_crc)?;
+    Ok(output)
+}
+
+// https://github.com/blakesmith/rombp/blob/master/docs/bps_spec.md
+fn apply_bps(rom: &[u8], patch: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    let out_of_range = CartridgeError::BadPatch("BPS action is out of range");
+    let target_crc = verify_footer(rom, patch)?;
+    let mut reader = Reader::new(&patch[..patch.len() - 12], 4);
+    let source_size = reader.varint()?;
+    let target_size = target_size(reader.varint()?)?;
+    let metadata_size = reader.varint()?;
+    reader.bytes(metadata_size)?;
+    if source_size != rom.len() {
+        return Err(CartridgeError::BadPatch("Source size mismatch"));
+    }
+
+    let mut output = vec![];
+    let mut source_offset: isize = 0;
+    let mut target_offset: isize = 0;
+    // Offsets move by signed amounts, and only the final ones are checked against the data
+    let seek = |offset: isize, delta: isize| offset.checked_add(delta).ok_or(out_of_range.clone());
+    let source = |start: usize, length: usize| {
+        start.checked_add(length).and_then(|end| rom.get(start..end)).ok_or(out_of_range.clone())
+    };
+    while reader.remaining() > 0 {
+        let data = reader.varint()?;
+        let length = (data >> 2) + 1;
+        // Every action writes length bytes
+        if length > target_size - output.len() {
+            return Err(CartridgeError::BadPatch("BPS output is larger than the target"));
+        }
+        match data & 0b11 {
+            // SourceRead
+            0 => output.extend_from_slice(source(output.len(), length)?),
+            // TargetRead
+            1 => output.extend_from_slice(reader.bytes(length)?),
+            // SourceCopy
+            2 => {
+                source_offset = seek(source_offset, reader.signed_varint()?)?;
+                let start = usize::try_from(source_offset).map_err(|_| out_of_range.clone())?;
+                output.extend_from_slice(source(start, length)?);
+                source_offset = seek(source_offset, length as isize)?;
+            }
+            // TargetCopy, may overlap the bytes being written
+            _ => {
+                target_offset = seek(target_offset, reader.signed_varint()?)?;
+                for _ in 0..length {
+                    let byte = usize::try_from(target_offset)
+                        .ok()
+                        .and_then(|i| output.get(i).copied())
+                        .ok_or(out_of_range.clone())?;
+                    output.push(byte);
+                    target_offset += 1;
+                }
+            }
+        }
+    }
+
+    if output.len() != target_size {
+        return Err(CartridgeError::BadPatch("Target size mismatch"));
+    }
+    verify_target(&output, target_crc)?;
+    Ok(output)
+}
+
+fn target_size(size: usize) -> Result<usize, CartridgeError> {
+    match size {
+        0..=MAX_TARGET_SIZE => Ok(size),
+        _ => Err(CartridgeError::BadPatch("Too large target size in patch")),
+    }
+}
+
+// UPS and BPS end with CRC32 of the source, the target and the patch itself.
+// Returns the CRC32 of the target.
+fn verify_footer(rom: &[u8], patch: &[u8]) -> Result<u32, CartridgeError> {
+    if patch.len() < 16 {
+        return Err(CartridgeError::BadPatch("Truncated patch"));
+    }
+    let footer = &patch[patch.len() - 12..];
+    let crc = |i: usize| u32::from_le_bytes([footer[i], footer[i + 1], footer[i + 2], footer[i + 3]]);
+    if crc32(&patch[..patch.len() - 4]) != crc(8) {
+        return Err(CartridgeError::BadPatch("Patch CRC32 mismatch"));
+    }
+    if crc32(rom) != crc(0) {
+        return Err(CartridgeError::BadPatch("Source CRC32 mismatch, the patch is for another ROM"));
+    }
+    Ok(crc(4))
+}
+
+fn verify_target(output: &[u8], target_crc: u32) -> Result<(), CartridgeError> {
+    if crc32(output) != target_crc {
+        return Err(CartridgeError::BadPatch("Target CRC32 mismatch"));
+    }
+    Ok(())
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [u8], offset: usize) -> Self {
+        Reader { data, offset }
+    }
+
+    fn remaining(&self) -> usize {
+        self.data.len().saturating_sub(self.offset)
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], CartridgeError> {
+        let bytes = self
+            .offset
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.offset..end))
+            .ok_or(CartridgeError::BadPatch("Truncated patch"))?;
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8, CartridgeError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    // Big endian
+    fn be(&mut self, len: usize) -> Result<usize, CartridgeError> {
+        Ok(self.bytes(len)?.iter().fold(0, |acc, b| acc << 8 | *b as usize))
+    }
+
+    // Variable length number used by UPS and BPS
+    fn varint(&mut self) -> Result<usize, CartridgeError> {
+        let mut data = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let x = self.byte()?;
+            data = data.wrapping_add((x & 0x7f) as usize * shift);
+            if x & 0x80 > 0 {
+                return Ok(data);
+            }
+            shift = shift.checked_mul(0x80).ok_or(CartridgeError::BadPatch("Too large number in patch"))?;
+            data = data.wrapping_add(shift);
+        }
+    }
+
+    // Lowest bit is the sign
+    fn signed_varint(&mut self) -> Result<isize, CartridgeError> {
+        let data = self.varint()?;
+        let value = (data >> 1) as isize;
+        Ok(if data & 1 > 0 { -value } else { value })
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    fn varint(mut data: usize) -> Vec<u8> {
+        let mut out = vec![];
+        loop {
+            let x = (data & 0x7f) as u8;
+            data >>= 7;
+            if data == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            data -= 1;
+        }
+    }
+
+    fn with_footer(mut patch: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        patch.extend_from_slice(&crc32(source).to_le_bytes());
+        patch.extend_from_slice(&crc32(target).to_le_bytes());
+        patch.extend_from_slice(&crc32(&patch).to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut patch = b"PATCH".to_vec();
+        // 2 bytes at 1
+        patch.extend_from_slice(&[0, 0, 1, 0, 2, 0xaa, 0xbb]);
+        // RLE of 3 bytes at 6, growing the image
+        patch.extend_from_slice(&[0, 0, 6, 0, 0, 0, 3, 0xcc]);
+        patch.extend_from_slice(b"EOF");
+        assert_eq!(apply_patch(&[0; 4], &patch).unwrap(), vec![0, 0xaa, 0xbb, 0, 0, 0, 0xcc, 0xcc, 0xcc]);
+
+        // Truncation extension
+        patch.extend_from_slice(&[0, 0, 2]);
+        assert_eq!(apply_patch(&[0; 4], &patch).unwrap(), vec![0, 0xaa]);
+
+        assert!(apply_patch(&[0; 4], b"PATCH\0\0\x01\0\x02").is_err());
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 7, 3, 4, 5];
+        let mut patch = b"UPS1".to_vec();
+        patch.extend(varint(4));
+        patch.extend(varint(5));
+        // Skip 1, XOR 2^7, terminator, then skip 1 and XOR 0^5
+        patch.extend(varint(1));
+        patch.extend_from_slice(&[2 ^ 7, 0]);
+        patch.extend(varint(1));
+        patch.extend_from_slice(&[5, 0]);
+        let patch = with_footer(patch, &source, &target);
+        assert_eq!(apply_patch(&source, &patch).unwrap(), target);
+
+        // Wrong source ROM
+        assert!(apply_patch(&[1, 2, 3, 5], &patch).is_err());
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"ABCDEF";
+        let target = b"ABCxyzxyzDEF";
+        let mut patch = b"BPS1".to_vec();
+        patch.extend(varint(source.len()));
+        patch.extend(varint(target.len()));
+        patch.extend(varint(0));
+        // SourceRead 3
+        patch.extend(varint((3 - 1) << 2));
+        // TargetRead "xyz"
+        patch.extend(varint((3 - 1) << 2 | 1));
+        patch.extend_from_slice(b"xyz");
+        // TargetCopy 3 from 3
+        patch.extend(varint((3 - 1) << 2 | 3));
+        patch.extend(varint(3 << 1));
+        // SourceCopy 3 from 3
+        patch.extend(varint((3 - 1) << 2 | 2));
+        patch.extend(varint(3 << 1));
+        let patch = with_footer(patch, source, target);
+        assert_eq!(apply_patch(source, &patch).unwrap(), target);
+
+        // Corrupted patch
+        let mut broken = patch.clone();
+        broken[8] ^= 1;
+        assert_eq!(apply_patch(source, &broken).err(), Some(CartridgeError::BadPatch("Patch CRC32 mismatch")));
+    }
+
+    fn header(magic: &[u8], source_size: usize, target_size: usize) -> Vec<u8> {
+        let mut patch = magic.to_vec();
+        patch.extend(varint(source_size));
+        patch.extend(varint(target_size));
+        patch
+    }
+
+    // Hostile sizes and offsets come with valid CRC32s
+    #[test]
+    fn test_malformed_patches() {
+        let source = [1, 2, 3, 4];
+        let error = |patch: Vec<u8>| apply_patch(&source, &with_footer(patch, &source, &source)).err();
+
+        let too_large = Some(CartridgeError::BadPatch("Too large target size in patch"));
+        assert_eq!(error(header(b"UPS1", 4, usize::MAX / 2)), too_large);
+        assert_eq!(error(header(b"BPS1", 4, usize::MAX / 2)), too_large);
+
+        // UPS offsets adding up past usize::MAX
+        let mut patch = header(b"UPS1", 4, 4);
+        for _ in 0..2 {
+            patch.extend(varint(usize::MAX / 2));
+            patch.push(0);
+        }
+        assert_eq!(error(patch), Some(CartridgeError::BadPatch("UPS offset is out of range")));
+
+        // BPS metadata longer than the address space
+        let mut patch = header(b"BPS1", 4, 4);
+        patch.extend(varint(usize::MAX - 8));
+        assert_eq!(error(patch), Some(CartridgeError::BadPatch("Truncated patch")));
+
+        // SourceCopy from the far end of the address space
+        let mut patch = header(b"BPS1", 4, 4);
+        patch.extend(varint(0));
+        patch.extend(varint((4 - 1) << 2 | 2));
+        patch.extend(varint((isize::MAX as usize) << 1));
+        assert_eq!(error(patch), Some(CartridgeError::BadPatch("BPS action is out of range")));
+
+        // TargetRead past the target size
+        let mut patch = header(b"BPS1", 4, 4);
+        patch.extend(varint(0));
+        patch.extend(varint((8 - 1) << 2 | 1));
+        patch.extend_from_slice(&[0; 8]);
+        assert_eq!(error(patch), Some(CartridgeError::BadPatch("BPS output is larger than the target")));
+    }
+}
@@ -0,0 +1,56 @@
+use crate::mapper::{bank_offset, Mapper, PRG_BANK_16K};
+
+// https://www.nesdev.org/wiki/UxROM
+pub struct Uxrom {
+    prg_rom_size: usize,
+    prg_bank: u8,
+    bus_conflicts: bool,
+}
+
+impl Uxrom {
+    pub fn new(prg_rom_size: usize, bus_conflicts: bool) -> Self {
+        Uxrom {
+            prg_rom_size,
+            prg_bank: 0,
+            bus_conflicts,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn prg_rom_offset(&self, address: u16) -> usize {
+        let address = (address - 0x8000) as usize;
+        if address < 0x4000 {
+            bank_offset(self.prg_bank as usize, PRG_BANK_16K, self.prg_rom_size) + address
+        } else {
+            // Fixed to the last bank
+            let last_bank = (self.prg_rom_size / PRG_BANK_16K).saturating_sub(1);
+            bank_offset(last_bank, PRG_BANK_16K, self.prg_rom_size) + address - 0x4000
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        address as usize
+    }
+
+    fn write_register(&mut self, _address: u16, data: u8) {
+        self.prg_bank = data;
+    }
+
+    fn has_bus_conflicts(&self) -> bool {
+        self.bus_conflicts
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    #[test]
+    fn test_uxrom_prg_banks() {
+        let mut uxrom = Uxrom::new(0x20000, true);
+        uxrom.write_register(0x8000, 2);
+        assert_eq!(uxrom.prg_rom_offset(0x8001), 0x8001);
+        assert_eq!(uxrom.prg_rom_offset(0xc001), 0x1c001);
+    }
+}
@@ -1,28 +1,74 @@
+mod memory;
 mod opcode;
 use crate::opcode::*;
 mod trace;
 
-use bus::{Bus, PPU_REG_OAM_DMA};
+#[cfg(feature = "nes")]
+use bus::Bus;
+pub use memory::{CpuBus, FlatMemory};
+
+const NMI_VECTOR: u16 = 0xfffa;
+const RESET_VECTOR: u16 = 0xfffc;
+const IRQ_VECTOR: u16 = 0xfffe;
+// Unstable opcodes mix A with a chip dependent value
+const LXA_MAGIC: u8 = 0xff;
+const XAA_MAGIC: u8 = 0xee;
+// OAM DMA writes to OAMDATA
+const OAM_DATA: u16 = 0x2004;
 
 #[cfg(test)]
 pub mod tests {
+    #[cfg(feature = "nes")]
     mod cpu_tests;
+    #[cfg(feature = "nes")]
+    mod rom_tests;
+    mod single_step_tests;
+}
+
+#[derive(Default, Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Flags {
+    pub n: bool,
+    pub v: bool,
+    pub x: bool,
+    pub b: bool,
+    pub d: bool,
+    pub i: bool,
+    pub z: bool,
+    pub c: bool,
 }
 
-#[derive(Default, Debug, Copy, Clone)]
-struct Flags {
-    n: bool,
-    v: bool,
-    x: bool,
-    b: bool,
-    d: bool,
-    i: bool,
-    z: bool,
-    c: bool,
+impl From<Flags> for u8 {
+    fn from(flags: Flags) -> u8 {
+        u8::from(flags.n) << 7
+            | u8::from(flags.v) << 6
+            | u8::from(flags.x) << 5
+            | u8::from(flags.b) << 4
+            | u8::from(flags.d) << 3
+            | u8::from(flags.i) << 2
+            | u8::from(flags.z) << 1
+            | u8::from(flags.c)
+    }
 }
 
-#[derive(Default)]
-pub struct Cpu {
+// The NES CPU is a 6502 with the decimal mode cut out. D can still be set and pushed,
+// but ADC and SBC stay binary.
+// https://www.nesdev.org/wiki/CPU
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Variant {
+    #[default]
+    Ricoh2A03,
+    // BCD arithmetic with the NMOS flags, to run plain 6502 programs
+    Nmos6502,
+}
+
+#[derive(PartialEq)]
+enum Access {
+    Read,
+    Write,
+    ReadModifyWrite,
+}
+
+pub struct Cpu<M> {
     //ram: Vec<u8>,
     pc: u16,
     sp: u8,
@@ -30,14 +76,85 @@ pub struct Cpu {
     x: u8,
     y: u8,
     f: Flags,
-    pub bus: Bus,
+    pub bus: M,
     total_cycles: usize,
+    // Returns from the run loop on BRK instead of taking the interrupt, for tests
+    halt_on_brk: bool,
+    variant: Variant,
+    jam: Option<Jam>,
+    // Interrupt lines sampled in the current and the previous cycle
+    need_nmi: bool,
+    prev_need_nmi: bool,
+    run_irq: bool,
+    prev_run_irq: bool,
+}
+
+// A JAM (KIL) opcode locks up the CPU until reset
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Jam {
+    pub opcode: u8,
+    pub pc: u16,
+}
+
+impl std::fmt::Display for Jam {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "CPU jammed by opcode {:02X} at {:04X}", self.opcode, self.pc)
+    }
 }
 
-impl Cpu {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HaltReason {
+    // BRK with set_halt_on_brk(true)
+    Brk,
+    Jam(Jam),
+}
+
+// Outcome of step_instruction() and run_frame()
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct StepResult {
+    // CPU cycles including interrupt sequences and OAM DMA
+    pub cycles: usize,
+    // The PPU has finished a frame, so the frame buffer can be shown
+    pub frame_completed: bool,
+    pub halt: Option<HaltReason>,
+}
+
+impl<M: CpuBus + Default> Cpu<M> {
     pub fn new() -> Self {
+        Cpu::with_bus(M::default())
+    }
+}
+
+impl<M: CpuBus + Default> Default for Cpu<M> {
+    fn default() -> Self {
+        Cpu::new()
+    }
+}
+
+#[cfg(feature = "nes")]
+impl Cpu<Bus> {
+    #[cfg(test)]
+    fn load_and_run(&mut self, program: Vec<u8>) {
+        self.bus.write_range(0x600, program);
+        self.set_halt_on_brk(true);
+        self.pc = 0x600 as u16;
+        self.run();
+    }
+
+    // Overrides the region given by the cartridge header
+    pub fn set_region(&mut self, region: cartridge::Region) {
+        self.bus.set_region(region);
+    }
+}
+
+impl<M: CpuBus> Cpu<M> {
+    pub fn with_bus(bus: M) -> Self {
         Cpu {
+            pc: 0,
             sp: 0xfd,
+            a: 0,
+            x: 0,
+            y: 0,
             f: Flags {
                 n: false,
                 v: false,
@@ -48,23 +165,24 @@ impl Cpu {
                 z: false,
                 c: false,
             },
+            bus,
             total_cycles: 7,
-            ..Default::default()
+            halt_on_brk: false,
+            variant: Variant::default(),
+            jam: None,
+            need_nmi: false,
+            prev_need_nmi: false,
+            run_irq: false,
+            prev_run_irq: false,
         }
     }
 
-    #[cfg(test)]
-    fn load_and_run(&mut self, program: Vec<u8>) {
-        self.bus.write_range(0x600, program);
-        self.pc = 0x600 as u16;
-        self.run();
-    }
-
     fn update_nz(&mut self, result: u8) {
         self.f.z = result == 0;
         self.f.n = result & 0b1000_0000 > 0;
     }
 
+    // Resolves the operand address without ticking, for trace()
     fn get_address(&mut self, mode: &AddressingMode) -> u16 {
         let operand_address = self.pc + 1;
         match mode {
@@ -111,73 +229,258 @@ impl Cpu {
         }
     }
 
-    fn page_crossed(&mut self, mode: &AddressingMode) -> bool {
+    // One CPU cycle. The PPU and APU run first, then the access happens and the interrupt
+    // lines are sampled at the end of the cycle.
+    fn begin_cycle(&mut self) {
+        self.total_cycles += 1;
+        self.bus.tick();
+    }
+
+    // The CPU acts on the lines sampled at the end of the second to last cycle of an instruction
+    // https://www.nesdev.org/wiki/CPU_interrupts#Detailed_interrupt_behavior
+    fn end_cycle(&mut self) {
+        self.prev_need_nmi = self.need_nmi;
+        if self.bus.take_nmi() {
+            self.need_nmi = true;
+        }
+        self.prev_run_irq = self.run_irq;
+        self.run_irq = !self.f.i && self.bus.irq();
+    }
+
+    fn idle_cycle(&mut self) {
+        self.begin_cycle();
+        self.end_cycle();
+    }
+
+    fn read(&mut self, address: u16) -> u8 {
+        self.begin_cycle();
+        let data = self.bus.read8(address);
+        self.end_cycle();
+        data
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.begin_cycle();
+        self.bus.write8(address, data);
+        self.end_cycle();
+        if let Some(page) = self.bus.take_oam_dma() {
+            self.oam_dma(page);
+        }
+    }
+
+    // The CPU is halted for 513 cycles, plus 1 to align with a read cycle
+    // https://www.nesdev.org/wiki/PPU_registers#OAMDMA
+    fn oam_dma(&mut self, page: u8) {
+        self.idle_cycle();
+        if self.total_cycles & 1 > 0 {
+            self.idle_cycle();
+        }
+        for i in 0..=0xff {
+            let data = self.read((page as u16) << 8 | i);
+            self.write(OAM_DATA, data);
+        }
+    }
+
+    fn fetch(&mut self) -> u8 {
+        let data = self.read(self.pc);
+        self.pc = self.pc.wrapping_add(1);
+        data
+    }
+
+    fn fetch16(&mut self) -> u16 {
+        let lo = self.fetch() as u16;
+        let hi = self.fetch() as u16;
+        hi << 8 | lo
+    }
+
+    // Implied and accumulator instructions read the next byte and throw it away
+    fn dummy_read(&mut self) {
+        self.read(self.pc);
+    }
+
+    // Fetches the operand and returns the effective address, with the dummy reads of each mode
+    // https://www.nesdev.org/6502_cpu.txt
+    fn fetch_address(&mut self, mode: &AddressingMode, access: Access) -> u16 {
         match mode {
-            AddressingMode::AbsoluteX | AddressingMode::AbsoluteY => {
-                (self.get_address(mode) ^ self.get_address(&AddressingMode::Absolute)) & 0xff00 > 0
+            AddressingMode::Immediate => {
+                let address = self.pc;
+                self.pc = self.pc.wrapping_add(1);
+                address
+            }
+            AddressingMode::ZeroPage => self.fetch() as u16,
+            AddressingMode::ZeroPageX | AddressingMode::ZeroPageY => {
+                let base = self.fetch();
+                self.read(base as u16);
+                let index = if mode == &AddressingMode::ZeroPageX { self.x } else { self.y };
+                base.wrapping_add(index) as u16
+            }
+            AddressingMode::Absolute => self.fetch16(),
+            AddressingMode::AbsoluteX => {
+                let base = self.fetch16();
+                self.indexed(base, self.x, access)
+            }
+            AddressingMode::AbsoluteY => {
+                let base = self.fetch16();
+                self.indexed(base, self.y, access)
+            }
+            AddressingMode::Indirect => {
+                // The pointer does not carry into the high byte
+                let ptr = self.fetch16();
+                let lo = self.read(ptr) as u16;
+                let hi = self.read(ptr & 0xff00 | ptr.wrapping_add(1) & 0x00ff) as u16;
+                hi << 8 | lo
+            }
+            AddressingMode::IndirectX => {
+                let ptr = self.fetch();
+                self.read(ptr as u16);
+                let ptr = ptr.wrapping_add(self.x);
+                let lo = self.read(ptr as u16) as u16;
+                let hi = self.read(ptr.wrapping_add(1) as u16) as u16;
+                hi << 8 | lo
             }
             AddressingMode::IndirectY => {
-                let address_lo = (self.get_address(mode) & 0x00ff) as u8;
-                self.y > address_lo
+                let ptr = self.fetch();
+                let lo = self.read(ptr as u16) as u16;
+                let hi = self.read(ptr.wrapping_add(1) as u16) as u16;
+                self.indexed(hi << 8 | lo, self.y, access)
+            }
+            AddressingMode::Relative | AddressingMode::Implied | AddressingMode::Accumulator => {
+                unreachable!()
             }
-            _ => false,
         }
     }
 
+    // The high byte is fixed up a cycle after the low byte is added, and the address before
+    // the fix is read meanwhile. Writes always take that cycle.
+    fn indexed(&mut self, base: u16, index: u8, access: Access) -> u16 {
+        let address = base.wrapping_add(index as u16);
+        if (base ^ address) & 0xff00 > 0 || access != Access::Read {
+            self.read(base & 0xff00 | address & 0x00ff);
+        }
+        address
+    }
+
+    fn read_operand(&mut self, mode: &AddressingMode) -> u8 {
+        let address = self.fetch_address(mode, Access::Read);
+        self.read(address)
+    }
+
+    fn store(&mut self, mode: &AddressingMode, data: u8) {
+        let address = self.fetch_address(mode, Access::Write);
+        self.write(address, data);
+    }
+
+    // Read-modify-write instructions write the original value back before the result
+    fn modify(&mut self, mode: &AddressingMode, operation: impl FnOnce(&mut Self, u8) -> u8) -> u8 {
+        if mode == &AddressingMode::Accumulator {
+            self.dummy_read();
+            self.a = operation(self, self.a);
+            return self.a;
+        }
+        let address = self.fetch_address(mode, Access::ReadModifyWrite);
+        let data = self.read(address);
+        self.write(address, data);
+        let result = operation(self, data);
+        self.write(address, result);
+        result
+    }
+
     fn push8(&mut self, data: u8) {
         let stack_address = (self.sp as u16) + 0x100;
-        self.bus.write8(stack_address, data);
+        self.write(stack_address, data);
         self.sp = self.sp.wrapping_sub(1);
     }
 
     #[must_use]
     fn pop8(&mut self) -> u8 {
-        let stack_address = (self.sp as u16) + 0x101;
-        let data = self.bus.read8(stack_address);
         self.sp = self.sp.wrapping_add(1);
-        data
+        let stack_address = (self.sp as u16) + 0x100;
+        self.read(stack_address)
     }
 
     fn push16(&mut self, data: u16) {
-        let stack_address = (self.sp as u16) + 0x100 - 1;
-        self.bus.write16(stack_address, data);
-        //println!("push16 {:X} @ {:X} -> {:X}", data, self.sp, self.sp.wrapping_sub(2));
-        self.sp = self.sp.wrapping_sub(2);
+        self.push8((data >> 8) as u8);
+        self.push8((data & 0xff) as u8);
     }
 
     #[must_use]
     fn pop16(&mut self) -> u16 {
-        let stack_address = (self.sp as u16) + 0x100 + 1;
-        let data = self.bus.read16(stack_address);
-        //println!("pop16 {:X} @ {:X} -> {:X}", data, self.sp, self.sp.wrapping_add(2));
-        self.sp = self.sp.wrapping_add(2);
-        data
+        let lo = self.pop8() as u16;
+        let hi = self.pop8() as u16;
+        hi << 8 | lo
+    }
+
+    // Reading the stack before the stack pointer moves
+    fn dummy_stack_read(&mut self) {
+        self.read((self.sp as u16) + 0x100);
     }
 
     fn intr_nmi(&mut self) {
+        self.dummy_read();
+        self.dummy_read();
+        self.interrupt(false);
+    }
+
+    fn intr_irq(&mut self) {
+        self.dummy_read();
+        self.dummy_read();
+        self.interrupt(false);
+    }
+
+    // BRK skips the padding byte after the opcode and pushes the flags with B set
+    // https://www.nesdev.org/wiki/Status_flags#The_B_flag
+    fn brk(&mut self) {
+        self.fetch();
+        self.interrupt(true);
+    }
+
+    // The vector is chosen after pushing, so an NMI arriving until then hijacks an IRQ or BRK,
+    // which then jumps to the NMI handler with the pushed flags unchanged.
+    // https://www.nesdev.org/wiki/CPU_interrupts#Interrupt_hijacking
+    fn interrupt(&mut self, b_flag: bool) {
         self.push16(self.pc);
 
         let mut flags = self.f;
-        flags.b = false;
+        flags.b = b_flag;
         flags.x = true;
-        let flags = u8::from(flags.n) << 7
-            | u8::from(flags.v) << 6
-            | u8::from(flags.x) << 5
-            | u8::from(flags.b) << 4
-            | u8::from(flags.d) << 3
-            | u8::from(flags.i) << 2
-            | u8::from(flags.z) << 1
-            | u8::from(flags.c) << 0;
-        self.push8(flags);
+        self.push8(flags.into());
 
-        self.f.i = false;
+        let vector = if self.need_nmi {
+            self.need_nmi = false;
+            NMI_VECTOR
+        } else {
+            IRQ_VECTOR
+        };
 
-        self.bus.tick(2);
+        self.f.i = true;
 
-        self.pc = self.bus.read16(0xfffa);
+        let lo = self.read(vector) as u16;
+        let hi = self.read(vector + 1) as u16;
+        self.pc = hi << 8 | lo;
+
+        // The first instruction of the handler always runs
+        self.prev_need_nmi = false;
+    }
+
+    // Polled at the end of each instruction. NMI wins over IRQ, which is level triggered
+    // and masked by the I flag. Both use the lines sampled in the second to last cycle,
+    // so CLI, SEI and PLP delay their effect by an instruction.
+    fn poll_interrupts(&mut self) {
+        if self.prev_need_nmi {
+            self.intr_nmi();
+        } else if self.prev_run_irq {
+            self.intr_irq();
+        }
     }
 
+    fn decimal_mode(&self) -> bool {
+        self.f.d && self.variant == Variant::Nmos6502
+    }
+
+    // Takes the inverted operand, so the binary part is the same as ADC
     fn sbc_impl(&mut self, data: u8) {
+        let a = self.a;
+        let c = self.f.c;
         let (data_plus_carry, overflow1) = data.overflowing_add(u8::from(self.f.c));
         let (result, overflow2) = self.a.overflowing_add(data_plus_carry);
 
@@ -187,9 +490,27 @@ impl Cpu {
         self.a = result;
         self.f.c = overflow1 || overflow2;
         self.update_nz(self.a);
+
+        // All flags come from the binary subtraction, only the result is adjusted
+        // http://www.6502.org/tutorials/decimal_mode.html#A
+        if self.decimal_mode() {
+            let data = !data as i16;
+            let mut lo = (a & 0x0f) as i16 - (data & 0x0f) - i16::from(!c);
+            if lo < 0 {
+                lo = ((lo - 0x06) & 0x0f) - 0x10;
+            }
+            let mut result = (a & 0xf0) as i16 - (data & 0xf0) + lo;
+            if result < 0 {
+                result -= 0x60;
+            }
+            self.a = result as u8;
+        }
     }
 
     fn adc_impl(&mut self, data: u8) {
+        if self.decimal_mode() {
+            return self.adc_decimal(data);
+        }
         let (data_plus_carry, overflow1) = data.overflowing_add(u8::from(self.f.c));
         let (result, overflow2) = self.a.overflowing_add(data_plus_carry);
 
@@ -201,21 +522,28 @@ impl Cpu {
         self.update_nz(self.a);
     }
 
-    fn adc(&mut self, mode: &AddressingMode) {
-        let address = self.get_address(mode);
-        let data = self.bus.read8(address);
-
-        self.adc_impl(data);
-    }
+    // Z comes from the binary sum, N and V from the sum before the high digit is adjusted
+    // http://www.6502.org/tutorials/decimal_mode.html#A
+    fn adc_decimal(&mut self, data: u8) {
+        let binary = self.a.wrapping_add(data).wrapping_add(u8::from(self.f.c));
 
-    fn sbc(&mut self, mode: &AddressingMode) {
-        let address = self.get_address(mode);
-        let data = !self.bus.read8(address);
-        self.sbc_impl(data);
+        let mut lo = (self.a & 0x0f) as u16 + (data & 0x0f) as u16 + u16::from(self.f.c);
+        if lo >= 0x0a {
+            lo = ((lo + 0x06) & 0x0f) + 0x10;
+        }
+        let mut result = (self.a & 0xf0) as u16 + (data & 0xf0) as u16 + lo;
+        let signed = (self.a & 0xf0) as i8 as i16 + (data & 0xf0) as i8 as i16 + lo as i16;
+        self.f.v = !(-128..=127).contains(&signed);
+        self.f.n = result & 0x80 > 0;
+        if result >= 0xa0 {
+            result += 0x60;
+        }
+        self.f.c = result >= 0x100;
+        self.f.z = binary == 0;
+        self.a = result as u8;
     }
 
-    fn plp(&mut self) {
-        let data = self.pop8();
+    fn plp(&mut self, data: u8) {
         self.f.n = data & 0b1000_0000 > 0;
         self.f.v = data & 0b0100_0000 > 0;
         self.f.x = true;
@@ -226,21 +554,73 @@ impl Cpu {
         self.f.c = data & 0b0000_0001 > 0;
     }
 
-    fn branch(&mut self, mode: &AddressingMode, condition: bool) -> u8 {
-        let mut cycle = 0;
-        if condition {
-            let address = self.get_address(mode);
-            let data = self.bus.read8(address) as i8;
+    fn asl_impl(&mut self, data: u8) -> u8 {
+        self.f.c = data & 0b1000_0000 > 0;
+        let result = data << 1;
+        self.update_nz(result);
+        result
+    }
 
-            let pc_add2 = self.pc.wrapping_add(2);
-            self.pc = self.pc.wrapping_add_signed(data as i16);
-            cycle += 1;
+    fn lsr_impl(&mut self, data: u8) -> u8 {
+        self.f.c = data & 0b1 > 0;
+        let result = data >> 1;
+        self.update_nz(result);
+        result
+    }
+
+    fn rol_impl(&mut self, data: u8) -> u8 {
+        let result = data << 1 | u8::from(self.f.c);
+        self.f.c = data & 0b1000_0000 > 0;
+        self.update_nz(result);
+        result
+    }
+
+    fn ror_impl(&mut self, data: u8) -> u8 {
+        let result = data >> 1 | u8::from(self.f.c) << 7;
+        self.f.c = data & 0b1 > 0;
+        self.update_nz(result);
+        result
+    }
 
-            if (self.pc ^ pc_add2) & 0xff00 > 0 {
-                cycle += 1;
+    fn compare(&mut self, register: u8, data: u8) {
+        self.update_nz(register.wrapping_sub(data));
+        self.f.c = register >= data;
+    }
+
+    // SHA, SHX, SHY and TAS store the data ANDed with the high byte of the base address plus 1.
+    // When the index crosses a page, that value also replaces the high byte of the address.
+    // https://www.nesdev.org/wiki/CPU_unofficial_opcodes
+    fn sh_store(&mut self, mode: &AddressingMode, data: u8) {
+        let address = self.fetch_address(mode, Access::Write);
+        let index = match mode {
+            AddressingMode::AbsoluteX => self.x,
+            _ => self.y,
+        };
+        let base = address.wrapping_sub(index as u16);
+        let data = data & ((base >> 8) as u8).wrapping_add(1);
+        let address = if (base ^ address) & 0xff00 > 0 {
+            (data as u16) << 8 | address & 0xff
+        } else {
+            address
+        };
+        self.write(address, data);
+    }
+
+    fn branch(&mut self, condition: bool) {
+        let data = self.fetch() as i8;
+        if condition {
+            // A taken branch without page crossing delays an IRQ arriving in it by an instruction
+            if self.run_irq && !self.prev_run_irq {
+                self.run_irq = false;
             }
+            self.dummy_read();
+
+            let target = self.pc.wrapping_add_signed(data as i16);
+            if (self.pc ^ target) & 0xff00 > 0 {
+                self.read(self.pc & 0xff00 | target & 0x00ff);
+            }
+            self.pc = target;
         }
-        cycle
     }
 
     #[cfg(test)]
@@ -252,462 +632,503 @@ impl Cpu {
         self.pc = pc;
     }
 
+    pub fn set_halt_on_brk(&mut self, halt: bool) {
+        self.halt_on_brk = halt;
+    }
+
+    // The reset button. RAM and A, X, Y survive, the stack pointer goes down by 3.
+    // https://www.nesdev.org/wiki/CPU_power_up_state
+    pub fn reset(&mut self) {
+        self.bus.reset();
+        self.jam = None;
+        self.need_nmi = false;
+        self.prev_need_nmi = false;
+        self.run_irq = false;
+        self.prev_run_irq = false;
+        self.reset_sequence();
+    }
+
+    // Power on state, with RAM cleared as most emulators do
+    pub fn power_cycle(&mut self) {
+        self.bus.power_cycle();
+        self.a = 0;
+        self.x = 0;
+        self.y = 0;
+        self.sp = 0;
+        self.f = Flags { x: true, ..Default::default() };
+        self.total_cycles = 0;
+        self.jam = None;
+        self.need_nmi = false;
+        self.prev_need_nmi = false;
+        self.run_irq = false;
+        self.prev_run_irq = false;
+        self.reset_sequence();
+    }
+
+    // An interrupt whose pushes are turned into reads, 7 cycles
+    fn reset_sequence(&mut self) {
+        self.dummy_read();
+        self.dummy_read();
+        for _ in 0..3 {
+            self.dummy_stack_read();
+            self.sp = self.sp.wrapping_sub(1);
+        }
+        self.f.i = true;
+        let lo = self.read(RESET_VECTOR) as u16;
+        let hi = self.read(RESET_VECTOR + 1) as u16;
+        self.pc = hi << 8 | lo;
+    }
+
+    pub fn set_variant(&mut self, variant: Variant) {
+        self.variant = variant;
+    }
+
+    pub fn jammed(&self) -> Option<Jam> {
+        self.jam
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> u8 {
+        self.sp
+    }
+
+    pub fn a(&self) -> u8 {
+        self.a
+    }
+
+    pub fn x(&self) -> u8 {
+        self.x
+    }
+
+    pub fn y(&self) -> u8 {
+        self.y
+    }
+
+    pub fn flags(&self) -> Flags {
+        self.f
+    }
+
+    // The P register, where B is not a real flag and reads as 0
+    pub fn status(&self) -> u8 {
+        self.f.into()
+    }
+
+    pub fn total_cycles(&self) -> usize {
+        self.total_cycles
+    }
+
+    // Runs until the PPU finishes a frame or the CPU halts
+    pub fn run_frame(&mut self) -> StepResult {
+        let mut result = StepResult::default();
+        while !result.frame_completed && result.halt.is_none() {
+            let step = self.step_instruction();
+            result.cycles += step.cycles;
+            result.frame_completed = step.frame_completed;
+            result.halt = step.halt;
+        }
+        result
+    }
+
     pub fn run_with_callback<F,F2>(&mut self, opaque: &mut dyn std::any::Any, mut callback: F, mut render_callback: F2)
     where
-        F: FnMut(&mut Cpu, &mut dyn std::any::Any),
-        F2: FnMut(&mut Cpu, &mut dyn std::any::Any),
+        F: FnMut(&mut Cpu<M>, &mut dyn std::any::Any),
+        F2: FnMut(&mut Cpu<M>, &mut dyn std::any::Any),
     {
         loop {
             callback(self, opaque);
-
-            // Read an opcode
-            let opcode_u8 = self.bus.read8(self.pc);
-            if !OPCODES.contains_key(&opcode_u8) {
-                println!("Unknown opcode {:X}", opcode_u8);
+            let result = self.step_instruction();
+            if result.frame_completed {
+                render_callback(self, opaque);
             }
-            let (opcode, mut cycles, mode, _) = &OPCODES[&opcode_u8];
+            if result.halt.is_some() {
+                return;
+            }
+        }
+    }
 
-            // Perform an operation
-            match opcode {
-                Opcodes::BRK => {
-                    self.pc += 1;
-                    return;
-                }
-                Opcodes::ADC => {
-                    self.adc(mode);
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::AND => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    self.a = self.a & data;
-                    self.update_nz(self.a);
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::ASL => {
-                    if mode == &AddressingMode::Accumulator {
-                        // Sets carry flag if bit7 of old value enabled
-                        self.f.c = self.a & 0b1000_0000 > 0;
-                        self.a = self.a.wrapping_mul(2);
-                        // Sets neg/zero flags with result value
-                        self.update_nz(self.a);
-                    } else {
-                        let address = self.get_address(mode);
-                        let data = self.bus.read8(address);
-                        self.f.c = data & 0b1000_0000 > 0;
-                        let result = data.wrapping_mul(2);
-                        self.bus.write8(address, result);
-                        self.update_nz(result);
-                    }
-                }
-                Opcodes::BCC => cycles += self.branch(mode, !self.f.c),
-                Opcodes::BCS => cycles += self.branch(mode, self.f.c),
-                Opcodes::BEQ => cycles += self.branch(mode, self.f.z),
-                Opcodes::BMI => cycles += self.branch(mode, self.f.n),
-                Opcodes::BNE => cycles += self.branch(mode, !self.f.z),
-                Opcodes::BPL => cycles += self.branch(mode, !self.f.n),
-                Opcodes::BVC => cycles += self.branch(mode, !self.f.v),
-                Opcodes::BVS => cycles += self.branch(mode, self.f.v),
-                Opcodes::BIT => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = self.a & data;
-                    self.f.z = result == 0;
-                    self.f.v = data & 0b0100_0000 > 0;
-                    self.f.n = data & 0b1000_0000 > 0;
-                }
-                Opcodes::CLC => self.f.c = false,
-                Opcodes::CLD => self.f.d = false,
-                Opcodes::CLI => self.f.i = false,
-                Opcodes::CLV => self.f.v = false,
-                Opcodes::CMP => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = self.a.wrapping_sub(data);
-                    self.update_nz(result);
-                    self.f.c = self.a >= data;
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::CPX => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = self.x.wrapping_sub(data);
-                    self.update_nz(result);
-                    self.f.c = self.x >= data;
-                }
-                Opcodes::CPY => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = self.y.wrapping_sub(data);
-                    self.update_nz(result);
-                    self.f.c = self.y >= data;
+    // Executes an instruction, then the interrupt sequence if one is pending
+    pub fn step_instruction(&mut self) -> StepResult {
+        // Only a reset brings the CPU back
+        if let Some(jam) = self.jam {
+            return StepResult { halt: Some(HaltReason::Jam(jam)), ..Default::default() };
+        }
+        let start_cycles = self.total_cycles;
+        let halt = self.execute();
+        if halt.is_none() {
+            self.poll_interrupts();
+        }
+        StepResult {
+            cycles: self.total_cycles - start_cycles,
+            frame_completed: self.bus.take_frame_ready(),
+            halt,
+        }
+    }
+
+    fn execute(&mut self) -> Option<HaltReason> {
+        // Read an opcode
+        let opcode_u8 = self.fetch();
+        let (opcode, _, mode, _) = &OPCODES[&opcode_u8];
+
+        // Perform an operation
+        match opcode {
+            Opcodes::BRK => {
+                if self.halt_on_brk {
+                    return Some(HaltReason::Brk);
                 }
-                Opcodes::DEC => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
+                self.brk();
+            }
+            Opcodes::ADC => {
+                let data = self.read_operand(mode);
+                self.adc_impl(data);
+            }
+            Opcodes::AND => {
+                let data = self.read_operand(mode);
+                self.a &= data;
+                self.update_nz(self.a);
+            }
+            Opcodes::ASL => {
+                self.modify(mode, Self::asl_impl);
+            }
+            Opcodes::BCC => self.branch(!self.f.c),
+            Opcodes::BCS => self.branch(self.f.c),
+            Opcodes::BEQ => self.branch(self.f.z),
+            Opcodes::BMI => self.branch(self.f.n),
+            Opcodes::BNE => self.branch(!self.f.z),
+            Opcodes::BPL => self.branch(!self.f.n),
+            Opcodes::BVC => self.branch(!self.f.v),
+            Opcodes::BVS => self.branch(self.f.v),
+            Opcodes::BIT => {
+                let data = self.read_operand(mode);
+                let result = self.a & data;
+                self.f.z = result == 0;
+                self.f.v = data & 0b0100_0000 > 0;
+                self.f.n = data & 0b1000_0000 > 0;
+            }
+            Opcodes::CLC => {
+                self.dummy_read();
+                self.f.c = false;
+            }
+            Opcodes::CLD => {
+                self.dummy_read();
+                self.f.d = false;
+            }
+            Opcodes::CLI => {
+                self.dummy_read();
+                self.f.i = false;
+            }
+            Opcodes::CLV => {
+                self.dummy_read();
+                self.f.v = false;
+            }
+            Opcodes::CMP => {
+                let data = self.read_operand(mode);
+                self.compare(self.a, data);
+            }
+            Opcodes::CPX => {
+                let data = self.read_operand(mode);
+                self.compare(self.x, data);
+            }
+            Opcodes::CPY => {
+                let data = self.read_operand(mode);
+                self.compare(self.y, data);
+            }
+            Opcodes::DEC => {
+                self.modify(mode, |cpu, data| {
                     let result = data.wrapping_sub(1);
-                    self.bus.write8(address, result);
-                    self.update_nz(result);
-                }
-                Opcodes::DEX => {
-                    self.x = self.x.wrapping_sub(1);
-                    self.update_nz(self.x);
-                }
-                Opcodes::DEY => {
-                    self.y = self.y.wrapping_sub(1);
-                    self.update_nz(self.y);
-                }
-                Opcodes::EOR => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    self.a = self.a ^ data;
-                    self.update_nz(self.a);
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::INC => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
+                    cpu.update_nz(result);
+                    result
+                });
+            }
+            Opcodes::DEX => {
+                self.dummy_read();
+                self.x = self.x.wrapping_sub(1);
+                self.update_nz(self.x);
+            }
+            Opcodes::DEY => {
+                self.dummy_read();
+                self.y = self.y.wrapping_sub(1);
+                self.update_nz(self.y);
+            }
+            Opcodes::EOR => {
+                let data = self.read_operand(mode);
+                self.a ^= data;
+                self.update_nz(self.a);
+            }
+            Opcodes::INC => {
+                self.modify(mode, |cpu, data| {
                     let result = data.wrapping_add(1);
-                    self.update_nz(result);
-                    self.bus.write8(address, result);
-                }
-                Opcodes::INX => {
-                    self.x = self.x.wrapping_add(1);
-                    self.update_nz(self.x);
-                }
-                Opcodes::INY => {
-                    self.y = self.y.wrapping_add(1);
-                    self.update_nz(self.y);
-                }
-                Opcodes::JMP => {
-                    let address = self.get_address(mode);
-                    self.pc = address;
-                }
-                Opcodes::JSR => {
-                    let address = self.get_address(mode);
-                    let stack_data = self.pc + MODE2BYTES[mode];
-                    self.push16(stack_data);
-                    self.pc = address;
-                }
-                Opcodes::LDA => {
-                    let address = self.get_address(mode);
-                    self.a = self.bus.read8(address);
-                    self.update_nz(self.a);
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::LDX => {
-                    let address = self.get_address(mode);
-                    self.x = self.bus.read8(address);
-                    self.update_nz(self.x);
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::LDY => {
-                    let address = self.get_address(mode);
-                    self.y = self.bus.read8(address);
-                    self.update_nz(self.y);
-                    cycles += u8::from(self.page_crossed(mode));
+                    cpu.update_nz(result);
+                    result
+                });
+            }
+            Opcodes::INX => {
+                self.dummy_read();
+                self.x = self.x.wrapping_add(1);
+                self.update_nz(self.x);
+            }
+            Opcodes::INY => {
+                self.dummy_read();
+                self.y = self.y.wrapping_add(1);
+                self.update_nz(self.y);
+            }
+            Opcodes::JMP => {
+                self.pc = self.fetch_address(mode, Access::Read);
+            }
+            Opcodes::JSR => {
+                // Pushes the address of the last byte of the instruction
+                let lo = self.fetch() as u16;
+                self.dummy_stack_read();
+                self.push16(self.pc);
+                let hi = self.read(self.pc) as u16;
+                self.pc = hi << 8 | lo;
+            }
+            Opcodes::LDA => {
+                self.a = self.read_operand(mode);
+                self.update_nz(self.a);
+            }
+            Opcodes::LDX => {
+                self.x = self.read_operand(mode);
+                self.update_nz(self.x);
+            }
+            Opcodes::LDY => {
+                self.y = self.read_operand(mode);
+                self.update_nz(self.y);
+            }
+            Opcodes::LSR => {
+                self.modify(mode, Self::lsr_impl);
+            }
+            Opcodes::NOP => {
+                if mode == &AddressingMode::Implied {
+                    self.dummy_read();
+                } else {
+                    self.read_operand(mode);
                 }
-                Opcodes::LSR => {
-                    if mode == &AddressingMode::Accumulator {
-                        self.f.c = self.a & 1 == 1;
-                        self.a >>= 1;
-                        self.update_nz(self.a);
-                    } else {
-                        let address = self.get_address(mode);
-                        let data = self.bus.read8(address);
-                        self.f.c = data & 1 == 1;
-                        let result = data >> 1;
-                        self.bus.write8(address, result);
-                        self.update_nz(result);
+            }
+            Opcodes::ORA => {
+                let data = self.read_operand(mode);
+                self.a |= data;
+                self.update_nz(self.a);
+            }
+            Opcodes::PHA => {
+                self.dummy_read();
+                self.push8(self.a);
+            }
+            Opcodes::PHP => {
+                self.dummy_read();
+                let mut flags = self.f;
+                // Always pushes 1 as the B flag
+                flags.b = true;
+                self.push8(flags.into());
+            }
+            Opcodes::PLA => {
+                self.dummy_read();
+                self.dummy_stack_read();
+                self.a = self.pop8();
+                self.update_nz(self.a);
+            }
+            Opcodes::PLP => {
+                self.dummy_read();
+                self.dummy_stack_read();
+                let data = self.pop8();
+                self.plp(data);
+            }
+            Opcodes::ROL => {
+                self.modify(mode, Self::rol_impl);
+            }
+            Opcodes::ROR => {
+                self.modify(mode, Self::ror_impl);
+            }
+            Opcodes::RTI => {
+                self.dummy_read();
+                self.dummy_stack_read();
+                let data = self.pop8();
+                self.plp(data);
+                self.pc = self.pop16();
+            }
+            Opcodes::RTS => {
+                self.dummy_read();
+                self.dummy_stack_read();
+                self.pc = self.pop16();
+                self.fetch();
+            }
+            Opcodes::SBC => {
+                let data = self.read_operand(mode);
+                self.sbc_impl(!data);
+            }
+            Opcodes::SEC => {
+                self.dummy_read();
+                self.f.c = true;
+            }
+            Opcodes::SED => {
+                self.dummy_read();
+                self.f.d = true;
+            }
+            Opcodes::SEI => {
+                self.dummy_read();
+                self.f.i = true;
+            }
+            Opcodes::STA => self.store(mode, self.a),
+            Opcodes::STX => self.store(mode, self.x),
+            Opcodes::STY => self.store(mode, self.y),
+            Opcodes::TAX => {
+                self.dummy_read();
+                self.x = self.a;
+                self.update_nz(self.x);
+            }
+            Opcodes::TAY => {
+                self.dummy_read();
+                self.y = self.a;
+                self.update_nz(self.y);
+            }
+            Opcodes::TSX => {
+                self.dummy_read();
+                self.x = self.sp;
+                self.update_nz(self.x);
+            }
+            Opcodes::TXA => {
+                self.dummy_read();
+                self.a = self.x;
+                self.update_nz(self.a);
+            }
+            Opcodes::TXS => {
+                self.dummy_read();
+                self.sp = self.x;
+            }
+            Opcodes::TYA => {
+                self.dummy_read();
+                self.a = self.y;
+                self.update_nz(self.a);
+            }
+            //
+            // Unofficial opcodes
+            //
+            Opcodes::ANC => {
+                let data = self.read_operand(mode);
+                self.a &= data;
+                self.update_nz(self.a);
+                self.f.c = self.f.n;
+            }
+            Opcodes::SAX => self.store(mode, self.a & self.x),
+            Opcodes::ARR => {
+                let data = self.read_operand(mode) & self.a;
+                self.a = data >> 1 | u8::from(self.f.c) << 7;
+                self.update_nz(self.a);
+
+                let bit6 = self.a & 0b0100_0000 > 0;
+                let bit5 = self.a & 0b0010_0000 > 0;
+                match (bit6, bit5) {
+                    (true, true) => {
+                        self.f.c = true;
+                        self.f.v = false;
                     }
-                }
-                Opcodes::NOP => {
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::ORA => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    self.a = self.a | data;
-                    self.update_nz(self.a);
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::PHA => self.push8(self.a),
-                Opcodes::PHP => {
-                    let data = u8::from(self.f.n) << 7
-                        | u8::from(self.f.v) << 6
-                        | u8::from(self.f.x) << 5
-                        | u8::from(true) << 4 // Always pushes 1 as the B flag
-                        | u8::from(self.f.d) << 3
-                        | u8::from(self.f.i) << 2
-                        | u8::from(self.f.z) << 1
-                        | u8::from(self.f.c) << 0;
-                    self.push8(data);
-                }
-                Opcodes::PLA => {
-                    self.a = self.pop8();
-                    self.update_nz(self.a);
-                }
-                Opcodes::PLP => self.plp(),
-                Opcodes::ROL => {
-                    if mode == &AddressingMode::Accumulator {
-                        let result = self.a << 1 | u8::from(self.f.c);
-                        self.f.c = self.a & 0b1000_0000 > 0;
-                        self.a = result;
-                        self.update_nz(self.a);
-                    } else {
-                        let address = self.get_address(mode);
-                        let data = self.bus.read8(address);
-                        let result = data << 1 | u8::from(self.f.c);
-                        self.f.c = data & 0b1000_0000 > 0;
-                        self.bus.write8(address, result);
-                        self.update_nz(result);
+                    (false, false) => {
+                        self.f.c = false;
+                        self.f.v = false;
                     }
-                }
-                Opcodes::ROR => {
-                    if mode == &AddressingMode::Accumulator {
-                        let result = self.a >> 1 | u8::from(self.f.c) << 7;
-                        self.f.c = self.a & 0b1 > 0;
-                        self.a = result;
-                        self.update_nz(self.a);
-                    } else {
-                        let address = self.get_address(mode);
-                        let data = self.bus.read8(address);
-                        let result = data >> 1 | u8::from(self.f.c) << 7;
-                        self.f.c = data & 0b1 > 0;
-                        self.bus.write8(address, result);
-                        self.update_nz(result);
+                    (true, false) => {
+                        self.f.c = true;
+                        self.f.v = true;
                     }
-                }
-                Opcodes::RTI => {
-                    self.plp();
-                    self.pc = self.pop16().wrapping_sub(1);
-                }
-                Opcodes::RTS => {
-                    self.pc = self.pop16();
-                }
-                Opcodes::SBC => {
-                    self.sbc(mode);
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::SEC => self.f.c = true,
-                Opcodes::SED => self.f.d = true,
-                Opcodes::SEI => self.f.i = true,
-                Opcodes::STA => {
-                    let address = self.get_address(mode);
-                    self.bus.write8(address, self.a);
-                    if address == PPU_REG_OAM_DMA {
-                        // Suspend 513 + 1 cycles
-                        self.bus.tick(64);
-                        self.bus.tick(64);
-                        self.bus.tick(64);
-                        self.bus.tick(64);
-                        self.bus.tick(64);
-                        self.bus.tick(64);
-                        self.bus.tick(64);
-                        self.bus.tick(64);
-                        cycles += 1;
-                        if self.total_cycles & 1 > 0 {
-                            cycles += 1;
-                        }
+                    (false, true) => {
+                        self.f.c = false;
+                        self.f.v = true;
                     }
-                }
-                Opcodes::STX => {
-                    let address = self.get_address(mode);
-                    self.bus.write8(address, self.x);
-                }
-                Opcodes::STY => {
-                    let address = self.get_address(mode);
-                    self.bus.write8(address, self.y);
-                }
-                Opcodes::TAX => {
-                    self.x = self.a;
-                    self.update_nz(self.x);
-                }
-                Opcodes::TAY => {
-                    self.y = self.a;
-                    self.update_nz(self.y);
-                }
-                Opcodes::TSX => {
-                    self.x = self.sp;
-                    self.update_nz(self.x);
-                }
-                Opcodes::TXA => {
-                    self.a = self.x;
-                    self.update_nz(self.a);
-                }
-                Opcodes::TXS => {
-                    self.sp = self.x;
-                }
-                Opcodes::TYA => {
-                    self.a = self.y;
-                    self.update_nz(self.a);
-                }
-                //
-                // Unofficial opcodes
-                //
-                Opcodes::AAC => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    self.a = self.a & data;
-                    self.update_nz(self.a);
-                    self.f.c = self.f.n;
-                }
-                Opcodes::SAX => {
-                    let address = self.get_address(mode);
-                    let result = self.a & self.x;
-                    // self.update_nz(result);
-                    self.bus.write8(address, result);
-                }
-                Opcodes::ARR => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    self.a = (self.a & data).rotate_right(1);
-
-                    let bit6 = self.a & 0b0100_0000 > 0;
-                    let bit5 = self.a & 0b0010_0000 > 0;
-                    match (bit6, bit5) {
-                        (true, true) => {
-                            self.f.c = true;
-                            self.f.v = false;
-                        }
-                        (false, false) => {
-                            self.f.c = false;
-                            self.f.v = false;
-                        }
-                        (true, false) => {
-                            self.f.c = true;
-                            self.f.v = true;
-                        }
-                        (false, true) => {
-                            self.f.c = false;
-                            self.f.v = true;
-                        }
-                    };
-                }
-                Opcodes::ASR => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    self.a = (self.a & data).rotate_right(1);
-                    self.f.c = self.a & 0b1000_0000 > 0;
-                    self.update_nz(self.a);
-                }
-                Opcodes::ATX => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    self.a = self.a & data;
-                    self.x = self.a;
-                    self.update_nz(self.x);
-                }
-                Opcodes::AXA => todo!(),
-                Opcodes::AXS => todo!(),
-                Opcodes::DCP => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    // DEC
-                    let result = data.wrapping_sub(1);
-                    self.bus.write8(address, result);
+                };
 
-                    // CMP
-                    let (result, overflow) = self.a.overflowing_sub(result);
-                    self.update_nz(result);
-                    self.f.c = !overflow;
+                // Each digit of the AND is fixed up separately, N, V and Z stay binary
+                // https://www.nesdev.org/6502_cpu.txt
+                if self.decimal_mode() {
+                    if (data & 0x0f) + (data & 0x01) > 0x05 {
+                        self.a = (self.a & 0xf0) | (self.a.wrapping_add(0x06) & 0x0f);
+                    }
+                    self.f.c = (data & 0xf0) as u16 + (data & 0x10) as u16 > 0x50;
+                    if self.f.c {
+                        self.a = self.a.wrapping_add(0x60);
+                    }
                 }
-                Opcodes::ISB => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = data.wrapping_add(1);
-                    self.bus.write8(address, result);
+            }
+            Opcodes::ALR => {
+                let data = self.read_operand(mode) & self.a;
+                self.a = self.lsr_impl(data);
+            }
+            Opcodes::LXA => {
+                let data = self.read_operand(mode);
+                self.a = (self.a | LXA_MAGIC) & data;
+                self.x = self.a;
+                self.update_nz(self.x);
+            }
+            Opcodes::SHA => self.sh_store(mode, self.a & self.x),
+            Opcodes::AXS => {
+                let data = self.read_operand(mode);
+                let (result, overflow) = (self.a & self.x).overflowing_sub(data);
+                self.x = result;
+                self.update_nz(self.x);
+                self.f.c = !overflow;
+            }
+            Opcodes::DCP => {
+                let result = self.modify(mode, |_, data| data.wrapping_sub(1));
+                self.compare(self.a, result);
+            }
+            Opcodes::ISB => {
+                let result = self.modify(mode, |_, data| data.wrapping_add(1));
+                self.sbc_impl(!result);
+            }
+            Opcodes::KIL => {
+                let pc = self.pc.wrapping_sub(1);
+                self.jam = Some(Jam { opcode: opcode_u8, pc });
+                self.pc = pc;
+                return self.jam.map(HaltReason::Jam);
+            }
+            Opcodes::LAS => {
+                let data = self.read_operand(mode);
+                let result = self.sp & data;
+                self.a = result;
+                self.x = result;
+                self.sp = result;
 
-                    self.sbc_impl(!result);
-                }
-                Opcodes::KIL => {
-                    return;
-                }
-                Opcodes::LAR => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = self.sp & data;
-                    self.a = result;
-                    self.x = result;
-                    self.sp = result;
-
-                    self.update_nz(self.sp);
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::LAX => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    self.a = data;
-                    self.x = data;
-                    self.update_nz(self.x);
-                    cycles += u8::from(self.page_crossed(mode));
-                }
-                Opcodes::RLA => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = data << 1 | u8::from(self.f.c);
-                    self.f.c = data & 0b1000_0000 > 0;
-                    self.bus.write8(address, result);
-
-                    self.a = self.a & result;
-                    self.update_nz(self.a);
-                }
-                Opcodes::RRA => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = data >> 1 | u8::from(self.f.c) << 7;
-                    self.bus.write8(address, result);
-                    self.f.c = data & 0b1 > 0;
-
-                    self.adc_impl(result);
-                }
-                Opcodes::SLO => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = data << 1;
-                    self.f.c = data & 0b1000_0000 > 0;
-                    self.bus.write8(address, result);
-
-                    self.a = self.a | result;
-                    self.update_nz(self.a);
-                }
-                Opcodes::SRE => {
-                    let address = self.get_address(mode);
-                    let data = self.bus.read8(address);
-                    let result = data >> 1;
-                    self.f.c = data & 0b1 > 0;
-                    self.bus.write8(address, result);
-
-                    self.a = self.a ^ result;
-                    self.update_nz(self.a);
-                }
-                Opcodes::SXA => todo!(),
-                Opcodes::SYA => todo!(),
-                Opcodes::XAA => todo!(),
-                Opcodes::XAS => todo!(),
-            }
-
-            // Consume some bytes except jumping
-            match opcode {
-                Opcodes::JMP | Opcodes::JSR => {}
-                // 1 for a opcode and rest for a operand
-                _ => self.pc += 1 + MODE2BYTES[mode],
-            }
-
-            self.total_cycles += cycles as usize;
-            // Tell current instruction's tick to PPU through bus
-            // and do callback or generate intr based on `TickResult`
-            let tick_results = self.bus.tick(cycles);
-            match &tick_results {
-                _ if tick_results.contains(&ppu::TickResult::ShouldInterruptNmiAndUpdateTexture) => {
-                    render_callback(self, opaque);
-                    self.intr_nmi();
-                }
-                _ if tick_results.contains(&ppu::TickResult::ShouldUpdateTexture) => {
-                    render_callback(self, opaque);
-                }
-                _ => {}
+                self.update_nz(self.sp);
+            }
+            Opcodes::LAX => {
+                let data = self.read_operand(mode);
+                self.a = data;
+                self.x = data;
+                self.update_nz(self.x);
+            }
+            Opcodes::RLA => {
+                let result = self.modify(mode, Self::rol_impl);
+                self.a &= result;
+                self.update_nz(self.a);
+            }
+            Opcodes::RRA => {
+                let result = self.modify(mode, Self::ror_impl);
+                self.adc_impl(result);
+            }
+            Opcodes::SLO => {
+                let result = self.modify(mode, Self::asl_impl);
+                self.a |= result;
+                self.update_nz(self.a);
+            }
+            Opcodes::SRE => {
+                let result = self.modify(mode, Self::lsr_impl);
+                self.a ^= result;
+                self.update_nz(self.a);
+            }
+            Opcodes::SHX => self.sh_store(mode, self.x),
+            Opcodes::SHY => self.sh_store(mode, self.y),
+            Opcodes::XAA => {
+                let data = self.read_operand(mode);
+                self.a = (self.a | XAA_MAGIC) & self.x & data;
+                self.update_nz(self.a);
+            }
+            Opcodes::TAS => {
+                self.sp = self.a & self.x;
+                self.sh_store(mode, self.sp);
             }
         }
+
+        None
     }
 }
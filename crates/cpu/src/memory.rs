@@ -0,0 +1,167 @@
+// What the CPU sees of the system. Plain 6502 code only needs read8() and write8(),
+// the other lines default to idle.
+pub trait CpuBus {
+    fn read8(&mut self, address: u16) -> u8;
+
+    fn write8(&mut self, address: u16, data: u8);
+
+    // Reads without side effects, for trace()
+    fn read8_trace(&mut self, address: u16) -> u8 {
+        self.read8(address)
+    }
+
+    fn read16(&mut self, address: u16) -> u16 {
+        let lo = self.read8(address) as u16;
+        let hi = self.read8(address.wrapping_add(1)) as u16;
+        hi << 8 | lo
+    }
+
+    fn read16_trace(&mut self, address: u16) -> u16 {
+        let lo = self.read8_trace(address) as u16;
+        let hi = self.read8_trace(address.wrapping_add(1)) as u16;
+        hi << 8 | lo
+    }
+
+    // Runs the rest of the system for one CPU cycle
+    fn tick(&mut self) {}
+
+    // NMI is edge triggered, so it is cleared once taken
+    fn take_nmi(&mut self) -> bool {
+        false
+    }
+
+    fn irq(&self) -> bool {
+        false
+    }
+
+    // Page written to $4014 on the 2A03, which the CPU then copies to OAM
+    fn take_oam_dma(&mut self) -> Option<u8> {
+        None
+    }
+
+    fn take_frame_ready(&mut self) -> bool {
+        false
+    }
+
+    fn reset(&mut self) {}
+
+    fn power_cycle(&mut self) {}
+
+    // Dot and scanline shown in the trace
+    fn ppu_position(&self) -> (usize, usize) {
+        (0, 0)
+    }
+}
+
+// 64KB of RAM and nothing else, for plain 6502 programs and test suites
+pub struct FlatMemory {
+    ram: Vec<u8>,
+}
+
+impl FlatMemory {
+    pub fn new() -> Self {
+        FlatMemory { ram: vec![0; 0x10000] }
+    }
+
+    pub fn load(&mut self, address: u16, data: &[u8]) {
+        let address = address as usize;
+        self.ram[address..address + data.len()].copy_from_slice(data);
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        FlatMemory::new()
+    }
+}
+
+impl CpuBus for FlatMemory {
+    fn read8(&mut self, address: u16) -> u8 {
+        self.ram[address as usize]
+    }
+
+    fn write8(&mut self, address: u16, data: u8) {
+        self.ram[address as usize] = data;
+    }
+}
+
+#[cfg(feature = "nes")]
+impl CpuBus for bus::Bus {
+    fn read8(&mut self, address: u16) -> u8 {
+        bus::Bus::read8(self, address)
+    }
+
+    fn write8(&mut self, address: u16, data: u8) {
+        bus::Bus::write8(self, address, data)
+    }
+
+    fn read8_trace(&mut self, address: u16) -> u8 {
+        bus::Bus::read8_trace(self, address)
+    }
+
+    fn read16(&mut self, address: u16) -> u16 {
+        bus::Bus::read16(self, address)
+    }
+
+    fn read16_trace(&mut self, address: u16) -> u16 {
+        bus::Bus::read16_trace(self, address)
+    }
+
+    fn tick(&mut self) {
+        bus::Bus::tick(self, 1);
+    }
+
+    fn take_nmi(&mut self) -> bool {
+        bus::Bus::take_nmi(self)
+    }
+
+    fn irq(&self) -> bool {
+        bus::Bus::irq(self)
+    }
+
+    fn take_oam_dma(&mut self) -> Option<u8> {
+        bus::Bus::take_oam_dma(self)
+    }
+
+    fn take_frame_ready(&mut self) -> bool {
+        bus::Bus::take_frame_ready(self)
+    }
+
+    fn reset(&mut self) {
+        bus::Bus::reset(self)
+    }
+
+    fn power_cycle(&mut self) {
+        bus::Bus::power_cycle(self)
+    }
+
+    fn ppu_position(&self) -> (usize, usize) {
+        self.ppu.get_cycles_scanlines()
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+    use crate::Cpu;
+
+    #[test]
+    fn test_flat_memory() {
+        let mut memory = FlatMemory::new();
+        // LDA #$42, STA $C000, INC $C000, BRK
+        memory.load(0x8000, &[0xa9, 0x42, 0x8d, 0x00, 0xc0, 0xee, 0x00, 0xc0, 0x00]);
+        memory.load(0xfffc, &[0x00, 0x80]);
+        let mut cpu = Cpu::with_bus(memory);
+        cpu.set_halt_on_brk(true);
+        cpu.power_cycle();
+        assert_eq!(cpu.pc(), 0x8000);
+
+        cpu.run();
+        assert_eq!(cpu.bus.read8(0xc000), 0x43);
+        // Writing $4014 is plain RAM, with no OAM DMA
+        cpu.bus.load(0x8000, &[0x8d, 0x14, 0x40, 0x00]);
+        cpu.set_pc(0x8000);
+        assert_eq!(cpu.step_instruction().cycles, 4);
+        assert_eq!(cpu.bus.read8(0x4014), 0x42);
+    }
+}
@@ -60,28 +60,28 @@ pub enum Opcodes {
     TXS,
     TYA,
     // Unofficial opcodes
-    AAC,
+    ANC,
     SAX,
     ARR,
-    ASR,
-    ATX,
-    AXA,
+    ALR,
+    LXA,
+    SHA,
     AXS,
     DCP,
     // DOP,
     ISB,
     KIL,
-    LAR,
+    LAS,
     LAX,
     RLA,
     RRA,
     SLO,
     SRE,
-    SXA,
-    SYA,
+    SHX,
+    SHY,
     // TOP,
     XAA,
-    XAS,
+    TAS,
 }
 
 #[derive(PartialEq, Eq, Hash)]
@@ -299,8 +299,8 @@ lazy_static! {
         // Unofficial opcodes
         //
 
-        (0x0b, (Opcodes::AAC, 2, AddressingMode::Immediate, false)),
-        (0x2b, (Opcodes::AAC, 2, AddressingMode::Immediate, false)),
+        (0x0b, (Opcodes::ANC, 2, AddressingMode::Immediate, false)),
+        (0x2b, (Opcodes::ANC, 2, AddressingMode::Immediate, false)),
 
         (0x87, (Opcodes::SAX, 3, AddressingMode::ZeroPage, false)),
         (0x97, (Opcodes::SAX, 4, AddressingMode::ZeroPageY, false)),
@@ -308,12 +308,12 @@ lazy_static! {
         (0x83, (Opcodes::SAX, 6, AddressingMode::IndirectX, false)),
 
         (0x6b, (Opcodes::ARR, 2, AddressingMode::Immediate, false)),
-        (0x4b, (Opcodes::ASR, 2, AddressingMode::Immediate, false)),
+        (0x4b, (Opcodes::ALR, 2, AddressingMode::Immediate, false)),
 
-        (0xab, (Opcodes::ATX, 2, AddressingMode::Immediate, false)),
+        (0xab, (Opcodes::LXA, 2, AddressingMode::Immediate, false)),
 
-        (0x9f, (Opcodes::AXA, 5, AddressingMode::AbsoluteY, false)),
-        (0x6b, (Opcodes::AXA, 6, AddressingMode::IndirectY, false)),
+        (0x9f, (Opcodes::SHA, 5, AddressingMode::AbsoluteY, false)),
+        (0x93, (Opcodes::SHA, 6, AddressingMode::IndirectY, false)),
 
         (0xcb, (Opcodes::AXS, 2, AddressingMode::Immediate, false)),
 
@@ -361,7 +361,7 @@ lazy_static! {
         (0xd2, (Opcodes::KIL, 0, AddressingMode::Implied, false)),
         (0xf2, (Opcodes::KIL, 0, AddressingMode::Implied, false)),
 
-        (0xbb, (Opcodes::LAR, 4, AddressingMode::AbsoluteY, false)),
+        (0xbb, (Opcodes::LAS, 4, AddressingMode::AbsoluteY, false)),
 
         (0xa7, (Opcodes::LAX, 3, AddressingMode::ZeroPage, false)),
         (0xb7, (Opcodes::LAX, 4, AddressingMode::ZeroPageY, false)),
@@ -411,9 +411,9 @@ lazy_static! {
         (0x43, (Opcodes::SRE, 8, AddressingMode::IndirectX, false)),
         (0x53, (Opcodes::SRE, 8, AddressingMode::IndirectY, false)),
 
-        (0x9e, (Opcodes::SXA, 4, AddressingMode::AbsoluteY, false)),
+        (0x9e, (Opcodes::SHX, 5, AddressingMode::AbsoluteY, false)),
 
-        (0x9c, (Opcodes::SYA, 4, AddressingMode::AbsoluteY, false)),
+        (0x9c, (Opcodes::SHY, 5, AddressingMode::AbsoluteX, false)),
 
         (0x0c, (Opcodes::NOP, 4, AddressingMode::Absolute, false)),
         (0x1c, (Opcodes::NOP, 4, AddressingMode::AbsoluteX, false)),
@@ -425,7 +425,7 @@ lazy_static! {
 
         (0x8b, (Opcodes::XAA, 2, AddressingMode::Immediate, false)),
 
-        (0x8b, (Opcodes::XAS, 5, AddressingMode::AbsoluteY, false)),
+        (0x9b, (Opcodes::TAS, 5, AddressingMode::AbsoluteY, false)),
     ]);
 
     pub static ref MODE2BYTES: HashMap<AddressingMode, u16> = HashMap::from([
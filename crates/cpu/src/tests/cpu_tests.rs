@@ -1,6 +1,11 @@
 #[cfg(test)]
 pub mod tests {
-    use crate::Cpu;
+    use crate::opcode::{AddressingMode, Opcodes, OPCODES};
+    use crate::{HaltReason, Jam, StepResult, Variant};
+    use bus::Bus;
+    use cartridge::Cartridge;
+
+    type Cpu = crate::Cpu<Bus>;
     #[test]
     fn test_0xa9_lda_immidiate_load_data() {
         let mut cpu = Cpu::new();
@@ -218,21 +223,22 @@ pub mod tests {
         cpu.a = 1;
         cpu.x = 2;
         cpu.y = 3;
+        cpu.set_halt_on_brk(true);
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(&mut 0, |cpu, _| {
             result.push(cpu.trace());
         },
         |_, _| {});
         assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD PPU:  0, 21 CYC:7",
             result[0]
         );
         assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD PPU:  0, 27 CYC:9",
             result[1]
         );
         assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD PPU:  0, 33 CYC:11",
             result[2]
         );
     }
@@ -253,14 +259,279 @@ pub mod tests {
 
         cpu.pc = 0x64;
         cpu.y = 0;
+        cpu.set_halt_on_brk(true);
         let mut result: Vec<String> = vec![];
         cpu.run_with_callback(&mut 0, |cpu, _| {
             result.push(cpu.trace());
         },
         |_, _| {});
         assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7",
             result[0]
         );
     }
+
+    // NROM with the NMI handler at 0x0680 and the IRQ handler at 0x0700
+    fn cpu_with_vectors() -> Cpu {
+        let mut raw = vec![b'N', b'E', b'S', 0x1a, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let mut prg = vec![0; 0x4000];
+        prg[0x3ffa..].copy_from_slice(&[0x80, 0x06, 0x00, 0x06, 0x00, 0x07]);
+        raw.extend(prg);
+        raw.extend(vec![0; 0x2000]);
+        let mut cpu = Cpu::new();
+        cpu.bus.load_cartridge(Cartridge::load(&raw).unwrap());
+        // BRK in both handlers
+        cpu.bus.write8(0x680, 0x00);
+        cpu.bus.write8(0x700, 0x00);
+        cpu
+    }
+
+    fn raise_frame_irq(cpu: &mut Cpu) {
+        cpu.bus.write8(0x4017, 0);
+        while !cpu.bus.irq() {
+            cpu.bus.tick(1);
+        }
+    }
+
+    #[test]
+    fn test_irq_masked_by_i_flag() {
+        let mut cpu = cpu_with_vectors();
+        raise_frame_irq(&mut cpu);
+        // I is set at power on
+        cpu.load_and_run(vec![0xa2, 0x01, 0x00]);
+        assert_eq!(cpu.pc, 0x603);
+        assert!(cpu.bus.irq_sources().apu_frame);
+    }
+
+    #[test]
+    fn test_irq_after_cli_latency() {
+        let mut cpu = cpu_with_vectors();
+        raise_frame_irq(&mut cpu);
+        // CLI, LDX #$01, BRK. LDX runs before the IRQ is taken
+        cpu.load_and_run(vec![0x58, 0xa2, 0x01, 0x00]);
+        assert_eq!(cpu.pc, 0x701);
+        assert_eq!(cpu.x, 1);
+        assert!(cpu.f.i);
+        // Return address and flags with B clear
+        assert_eq!(cpu.bus.read16(0x1fc), 0x603);
+        assert_eq!(cpu.bus.read8(0x1fb) & 0b0011_0100, 0b0010_0000);
+
+        // Reading $4015 acknowledges the frame IRQ
+        assert_eq!(cpu.bus.read8(0x4015) & 0b0100_0000, 0b0100_0000);
+        assert!(!cpu.bus.irq());
+    }
+
+    #[test]
+    fn test_nmi_hijacks_irq() {
+        let mut cpu = cpu_with_vectors();
+        cpu.bus.write8(0x2000, 0x80);
+        while !cpu.bus.take_frame_ready() {
+            cpu.bus.tick(1);
+        }
+        cpu.pc = 0x600;
+        cpu.intr_irq();
+        assert_eq!(cpu.pc, 0x680);
+        // The NMI has been consumed by the IRQ sequence
+        assert!(!cpu.bus.take_nmi());
+        assert_eq!(cpu.bus.read8(0x1fb) & 0b0001_0000, 0);
+    }
+
+    #[test]
+    fn test_brk() {
+        let mut cpu = cpu_with_vectors();
+        // KIL in the IRQ handler to stop there
+        cpu.bus.write8(0x700, 0x02);
+        // BRK and its padding byte
+        cpu.bus.write_range(0x600, vec![0x00, 0xff]);
+        cpu.pc = 0x600;
+        cpu.run();
+        assert_eq!(cpu.pc, 0x700);
+        assert!(cpu.f.i);
+        assert_eq!(cpu.bus.read16(0x1fc), 0x602);
+        assert_eq!(cpu.bus.read8(0x1fb) & 0b0011_0000, 0b0011_0000);
+    }
+
+    #[test]
+    fn test_unofficial_opcodes() {
+        // ALR #$03
+        let mut cpu = Cpu::new();
+        cpu.load_and_run(vec![0xa9, 0xff, 0x4b, 0x03, 0x00]);
+        assert_eq!(cpu.a, 0x01);
+        assert!(cpu.f.c);
+
+        // ARR #$ff with carry rotated in
+        let mut cpu = Cpu::new();
+        cpu.load_and_run(vec![0x38, 0xa9, 0xff, 0x6b, 0xff, 0x00]);
+        assert_eq!(cpu.a, 0xff);
+        assert!(cpu.f.c);
+        assert!(!cpu.f.v);
+        assert!(cpu.f.n);
+
+        // AXS #$02
+        let mut cpu = Cpu::new();
+        cpu.load_and_run(vec![0xa9, 0x0f, 0xa2, 0xf3, 0xcb, 0x02, 0x00]);
+        assert_eq!(cpu.x, 0x01);
+        assert!(cpu.f.c);
+
+        // SHX $02ff,Y crossing a page writes to $0100
+        let mut cpu = Cpu::new();
+        cpu.load_and_run(vec![0xa2, 0x05, 0xa0, 0x01, 0x9e, 0xff, 0x02, 0x00]);
+        assert_eq!(cpu.bus.read8(0x100), 0x01);
+        assert_eq!(cpu.bus.read8(0x300), 0x00);
+    }
+
+    #[test]
+    fn test_jam() {
+        let mut cpu = Cpu::new();
+        cpu.load_and_run(vec![0xe8, 0x02, 0xe8]);
+        assert_eq!(cpu.jammed(), Some(Jam { opcode: 0x02, pc: 0x601 }));
+        cpu.run();
+        assert_eq!(cpu.x, 1);
+    }
+
+    #[test]
+    fn test_cycles_match_opcode_table() {
+        for (code, (opcode, cycles, mode, _)) in OPCODES.iter() {
+            match (opcode, mode) {
+                (Opcodes::BRK | Opcodes::KIL | Opcodes::JMP | Opcodes::JSR | Opcodes::RTS | Opcodes::RTI, _)
+                | (_, AddressingMode::Relative) => continue,
+                _ => {}
+            }
+            // Operands of zero, followed by BRK
+            let mut cpu = Cpu::new();
+            cpu.load_and_run(vec![*code, 0x00, 0x00, 0x00]);
+            // Minus the initial 7 cycles and the fetch of BRK
+            assert_eq!(cpu.total_cycles - 7 - 1, *cycles as usize, "{:02X} {:?}", code, opcode);
+        }
+    }
+
+    #[test]
+    fn test_page_crossing_and_oam_dma_cycles() {
+        // LDX #$01, LDA $00ff,X takes a cycle more than LDA $0000,X
+        let mut cpu = Cpu::new();
+        cpu.load_and_run(vec![0xa2, 0x01, 0xbd, 0xff, 0x00, 0x00]);
+        assert_eq!(cpu.total_cycles - 7 - 1, 2 + 5);
+
+        // LDA #$02, STA $4014 halts for 513 or 514 cycles
+        let mut cpu = Cpu::new();
+        cpu.bus.write8(0x0203, 0xaa);
+        cpu.load_and_run(vec![0xa9, 0x02, 0x8d, 0x14, 0x40, 0x00]);
+        assert!([513, 514].contains(&(cpu.total_cycles - 7 - 1 - 2 - 4)));
+        assert_eq!(cpu.bus.ppu.oam_data[3], 0xaa);
+    }
+
+    #[test]
+    fn test_step_instruction() {
+        let mut cpu = Cpu::new();
+        cpu.set_halt_on_brk(true);
+        // LDX #$01, INX, BRK
+        cpu.bus.write_range(0x600, vec![0xa2, 0x01, 0xe8, 0x00]);
+        cpu.set_pc(0x600);
+
+        let result = cpu.step_instruction();
+        assert_eq!(result, StepResult { cycles: 2, frame_completed: false, halt: None });
+        assert_eq!((cpu.pc(), cpu.x()), (0x602, 1));
+        cpu.step_instruction();
+        assert_eq!(cpu.x(), 2);
+        assert_eq!(cpu.step_instruction().halt, Some(HaltReason::Brk));
+        assert_eq!(cpu.status(), 0x24);
+        assert!(cpu.flags().i);
+    }
+
+    #[test]
+    fn test_run_frame() {
+        let mut cpu = Cpu::new();
+        // JMP $0600
+        cpu.bus.write_range(0x600, vec![0x4c, 0x00, 0x06]);
+        cpu.set_pc(0x600);
+
+        let result = cpu.run_frame();
+        assert!(result.frame_completed);
+        assert_eq!(result.halt, None);
+        // A whole frame takes 29780.5 cycles, but the PPU starts at dot 21 of the first frame
+        let result = cpu.run_frame();
+        assert!((29780..=29783).contains(&result.cycles));
+
+        // JAM stops it
+        cpu.bus.write8(0x600, 0x02);
+        cpu.set_pc(0x600);
+        let jam = Jam { opcode: 0x02, pc: 0x600 };
+        assert_eq!(cpu.run_frame().halt, Some(HaltReason::Jam(jam)));
+        assert_eq!(cpu.step_instruction(), StepResult { cycles: 0, frame_completed: false, halt: Some(HaltReason::Jam(jam)) });
+    }
+
+    #[test]
+    fn test_reset_and_power_cycle() {
+        let mut cpu = cpu_with_vectors();
+        // LDA #$42, LDX #$01, CLI, BRK
+        cpu.load_and_run(vec![0xa9, 0x42, 0xa2, 0x01, 0x58, 0x00]);
+        cpu.bus.write8(0x10, 0x55);
+        cpu.bus.write8(0x4015, 0b1111);
+        cpu.bus.write8(0x4003, 0b1111_1000);
+        let sp = cpu.sp();
+        let cycles = cpu.total_cycles();
+
+        // RAM and registers survive, the stack pointer goes down by 3
+        cpu.reset();
+        assert_eq!(cpu.pc(), 0x600);
+        assert_eq!((cpu.a(), cpu.x()), (0x42, 1));
+        assert_eq!(cpu.sp(), sp.wrapping_sub(3));
+        assert!(cpu.flags().i);
+        assert_eq!(cpu.total_cycles(), cycles + 7);
+        assert_eq!(cpu.bus.read8(0x10), 0x55);
+        // $4015 is written with 0
+        assert_eq!(cpu.bus.read8(0x4015) & 0b1111, 0);
+
+        cpu.power_cycle();
+        assert_eq!(cpu.pc(), 0x600);
+        assert_eq!((cpu.a(), cpu.x(), cpu.y(), cpu.sp()), (0, 0, 0, 0xfd));
+        assert_eq!(cpu.status(), 0x24);
+        assert_eq!(cpu.total_cycles(), 7);
+        assert_eq!(cpu.bus.read8(0x10), 0);
+    }
+
+    fn run_decimal(variant: Variant, program: Vec<u8>) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.set_variant(variant);
+        // SED first
+        cpu.load_and_run([vec![0xf8], program].concat());
+        cpu
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+        // CLC, LDA #$58, ADC #$46
+        let cpu = run_decimal(Variant::Nmos6502, vec![0x18, 0xa9, 0x58, 0x69, 0x46, 0x00]);
+        assert_eq!(cpu.a(), 0x04);
+        assert!(cpu.flags().c);
+        // The 2A03 ignores D
+        let cpu = run_decimal(Variant::Ricoh2A03, vec![0x18, 0xa9, 0x58, 0x69, 0x46, 0x00]);
+        assert_eq!(cpu.a(), 0x9e);
+        assert!(!cpu.flags().c);
+
+        // $99 + $01: N comes from $A0 and Z from the binary $9A
+        let cpu = run_decimal(Variant::Nmos6502, vec![0x18, 0xa9, 0x99, 0x69, 0x01, 0x00]);
+        assert_eq!(cpu.a(), 0x00);
+        let flags = cpu.flags();
+        assert!(flags.c && flags.n && !flags.z && !flags.v);
+
+        // SEC, LDA #$40, SBC #$13
+        let cpu = run_decimal(Variant::Nmos6502, vec![0x38, 0xa9, 0x40, 0xe9, 0x13, 0x00]);
+        assert_eq!(cpu.a(), 0x27);
+        assert!(cpu.flags().c);
+        // SEC, LDA #$12, SBC #$21 borrows
+        let cpu = run_decimal(Variant::Nmos6502, vec![0x38, 0xa9, 0x12, 0xe9, 0x21, 0x00]);
+        assert_eq!(cpu.a(), 0x91);
+        let flags = cpu.flags();
+        assert!(!flags.c && flags.n);
+        // CLC, LDA #$00, SBC #$00 borrows from the carry too
+        let cpu = run_decimal(Variant::Nmos6502, vec![0x18, 0xa9, 0x00, 0xe9, 0x00, 0x00]);
+        assert_eq!(cpu.a(), 0x99);
+        assert!(!cpu.flags().c);
+
+        // CLC, LDA #$FF, ARR #$FF rotates to $7F, then both digits are fixed up
+        let cpu = run_decimal(Variant::Nmos6502, vec![0x18, 0xa9, 0xff, 0x6b, 0xff, 0x00]);
+        assert_eq!(cpu.a(), 0xd5);
+        assert!(cpu.flags().c);
+    }
 }
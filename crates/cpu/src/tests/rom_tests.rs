@@ -0,0 +1,148 @@
+// Runs test ROMs that report through memory. Put blargg's sprite_hit_tests_2005.10.05
+// ROMs in crates/cpu/tests/sprite_hit_tests, or point SPRITE_HIT_TESTS to them, then run
+//   cargo test -p cpu --release sprite_hit -- --ignored
+// They leave the result code at $F8: 1 for a pass, otherwise the number of the failed check.
+//
+// cpu_dummy_reads.nes goes in crates/cpu/tests/cpu_dummy_reads (CPU_DUMMY_READS), and the
+// rom_singles of ppu_vbl_nmi in crates/cpu/tests/ppu_vbl_nmi (PPU_VBL_NMI). These report
+// through PRG-RAM instead: $6000 holds $80 while running, $81 when the reset button has
+// to be pressed and the result code after that, 0 for a pass. $6001-$6003 are DE B0 61
+// once the status is valid and $6004 starts the text of the result.
+//
+// The ROMs are not in the repository, so the tests are ignored by default.
+#[cfg(test)]
+pub mod tests {
+    use bus::Bus;
+    use cartridge::Cartridge;
+    use std::path::{Path, PathBuf};
+
+    type Cpu = crate::Cpu<Bus>;
+
+    const RESULT: u16 = 0x00f8;
+    // Each ROM is done within a couple of seconds
+    const FRAMES: usize = 240;
+    const STATUS: u16 = 0x6000;
+    const SIGNATURE: [u8; 3] = [0xde, 0xb0, 0x61];
+    const RUNNING: u8 = 0x80;
+    const NEEDS_RESET: u8 = 0x81;
+    // Gives up after 30 seconds
+    const STATUS_FRAMES: usize = 60 * 30;
+    // Reset is to be pressed no sooner than 100 ms after $81 is written
+    const RESET_DELAY: usize = 6;
+
+    fn rom_dir(var: &str, default: &str) -> PathBuf {
+        match std::env::var(var) {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(default),
+        }
+    }
+
+    fn roms(dir: &Path) -> Vec<PathBuf> {
+        let mut roms: Vec<PathBuf> = std::fs::read_dir(dir)
+            .map(|entries| entries.filter_map(|entry| entry.ok().map(|entry| entry.path())).collect())
+            .unwrap_or_default();
+        roms.retain(|path| path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("nes")));
+        roms.sort();
+        roms
+    }
+
+    fn run_rom(path: &Path) -> u8 {
+        let raw = std::fs::read(path).expect("Failed to read the ROM");
+        let mut cpu = Cpu::new();
+        cpu.bus.load_cartridge(Cartridge::load(&raw).expect("Invalid ROM"));
+        cpu.power_cycle();
+        for _ in 0..FRAMES {
+            if cpu.run_frame().halt.is_some() {
+                break;
+            }
+        }
+        cpu.bus.read8(RESULT)
+    }
+
+    fn status(cpu: &mut Cpu) -> Option<u8> {
+        let signature = [0x6001, 0x6002, 0x6003].map(|address| cpu.bus.read8(address));
+        (signature == SIGNATURE).then(|| cpu.bus.read8(STATUS))
+    }
+
+    fn status_text(cpu: &mut Cpu) -> String {
+        let text: Vec<u8> = (0x6004..0x8000)
+            .map(|address| cpu.bus.read8(address))
+            .take_while(|data| *data != 0)
+            .collect();
+        String::from_utf8_lossy(&text).trim().to_string()
+    }
+
+    // Returns the result code and the text, or None when the ROM never finishes
+    fn run_status_rom(path: &Path) -> Option<(u8, String)> {
+        let raw = std::fs::read(path).expect("Failed to read the ROM");
+        let mut cpu = Cpu::new();
+        cpu.bus.load_cartridge(Cartridge::load(&raw).expect("Invalid ROM"));
+        cpu.power_cycle();
+        let mut reset_at = None;
+        for frame in 0..STATUS_FRAMES {
+            if cpu.run_frame().halt.is_some() {
+                break;
+            }
+            match status(&mut cpu) {
+                None | Some(RUNNING) => {}
+                Some(NEEDS_RESET) => {
+                    let at = *reset_at.get_or_insert(frame + RESET_DELAY);
+                    if frame >= at {
+                        cpu.reset();
+                        reset_at = None;
+                    }
+                }
+                Some(result) => return Some((result, status_text(&mut cpu))),
+            }
+        }
+        None
+    }
+
+    fn check_status_roms(dir: &Path) {
+        let roms = roms(dir);
+        assert!(!roms.is_empty(), "No test ROMs in {}", dir.display());
+
+        let failures: Vec<String> = roms
+            .iter()
+            .filter_map(|path| {
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                match run_status_rom(path) {
+                    Some((0, _)) => None,
+                    Some((result, text)) => Some(format!("{}: #{} {}", name, result, text)),
+                    None => Some(format!("{}: did not finish", name)),
+                }
+            })
+            .collect();
+        assert!(failures.is_empty(), "{} of {} ROMs failed\n{}", failures.len(), roms.len(), failures.join("\n"));
+    }
+
+    #[test]
+    #[ignore = "needs the sprite_hit_tests ROMs, see the top of rom_tests.rs"]
+    fn test_sprite_hit_roms() {
+        let dir = rom_dir("SPRITE_HIT_TESTS", "tests/sprite_hit_tests");
+        let roms = roms(&dir);
+        assert!(!roms.is_empty(), "No sprite hit test ROMs in {}", dir.display());
+
+        let failures: Vec<String> = roms
+            .iter()
+            .filter_map(|path| {
+                let result = run_rom(path);
+                let name = path.file_name().unwrap_or_default().to_string_lossy();
+                (result != 1).then(|| format!("{}: #{}", name, result))
+            })
+            .collect();
+        assert!(failures.is_empty(), "{} of {} ROMs failed\n{}", failures.len(), roms.len(), failures.join("\n"));
+    }
+
+    #[test]
+    #[ignore = "needs the cpu_dummy_reads ROM, see the top of rom_tests.rs"]
+    fn test_cpu_dummy_reads_rom() {
+        check_status_roms(&rom_dir("CPU_DUMMY_READS", "tests/cpu_dummy_reads"));
+    }
+
+    #[test]
+    #[ignore = "needs the ppu_vbl_nmi ROMs, see the top of rom_tests.rs"]
+    fn test_ppu_vbl_nmi_roms() {
+        check_status_roms(&rom_dir("PPU_VBL_NMI", "tests/ppu_vbl_nmi"));
+    }
+}
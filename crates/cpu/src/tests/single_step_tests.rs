@@ -0,0 +1,258 @@
+// Runs the per-opcode JSON vectors of https://github.com/SingleStepTests/65x02
+// Each test gives the registers and RAM before and after one instruction, and the bus
+// access of every cycle. Put the files (00.json .. ff.json) of the nes6502 set in
+// crates/cpu/tests/vectors, or point CPU_TEST_VECTORS to them. CPU_TEST_VARIANT=nmos
+// runs the 6502 set, which has the decimal mode. The JAM opcodes lock up the CPU while
+// the vectors keep reading the bus, so for those only the halt and the PC are checked.
+// The test is ignored by default, run it with
+// `cargo test -p cpu --release single_step -- --ignored`.
+#[cfg(test)]
+pub mod tests {
+    use crate::{Cpu, CpuBus, FlatMemory, Jam, Variant};
+    use serde_json::Value;
+    use std::path::PathBuf;
+
+    // Lock up the CPU
+    const JAM_OPCODES: [u8; 12] = [0x02, 0x12, 0x22, 0x32, 0x42, 0x52, 0x62, 0x72, 0x92, 0xb2, 0xd2, 0xf2];
+    // Only a few failures are printed in full
+    const MAX_REPORTS: usize = 20;
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    struct Cycle {
+        address: u16,
+        data: u8,
+        write: bool,
+    }
+
+    impl std::fmt::Display for Cycle {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            let access = if self.write { "write" } else { "read" };
+            write!(f, "{} ${:04X} = ${:02X}", access, self.address, self.data)
+        }
+    }
+
+    // Flat RAM that records the bus activity
+    #[derive(Default)]
+    struct RecordingBus {
+        memory: FlatMemory,
+        cycles: Vec<Cycle>,
+    }
+
+    impl CpuBus for RecordingBus {
+        fn read8(&mut self, address: u16) -> u8 {
+            let data = self.memory.read8(address);
+            self.cycles.push(Cycle { address, data, write: false });
+            data
+        }
+
+        fn write8(&mut self, address: u16, data: u8) {
+            self.memory.write8(address, data);
+            self.cycles.push(Cycle { address, data, write: true });
+        }
+    }
+
+    fn number(value: &Value) -> u64 {
+        value.as_u64().expect("Expected a number")
+    }
+
+    fn set_state(cpu: &mut Cpu<RecordingBus>, state: &Value) {
+        cpu.pc = number(&state["pc"]) as u16;
+        cpu.sp = number(&state["s"]) as u8;
+        cpu.a = number(&state["a"]) as u8;
+        cpu.x = number(&state["x"]) as u8;
+        cpu.y = number(&state["y"]) as u8;
+        cpu.plp(number(&state["p"]) as u8);
+        for entry in state["ram"].as_array().unwrap() {
+            cpu.bus.memory.write8(number(&entry[0]) as u16, number(&entry[1]) as u8);
+        }
+    }
+
+    // Returns the fields that differ from the expected state
+    fn diff_state(cpu: &mut Cpu<RecordingBus>, state: &Value, cycles: &[Cycle]) -> Vec<String> {
+        let mut diffs = vec![];
+        // B and bit 5 are not in the register
+        let registers = [
+            ("pc", cpu.pc as u64, number(&state["pc"])),
+            ("s", cpu.sp as u64, number(&state["s"])),
+            ("a", cpu.a as u64, number(&state["a"])),
+            ("x", cpu.x as u64, number(&state["x"])),
+            ("y", cpu.y as u64, number(&state["y"])),
+            ("p", (cpu.status() & 0xcf) as u64, number(&state["p"]) & 0xcf),
+        ];
+        for (name, actual, expected) in registers {
+            if actual != expected {
+                diffs.push(format!("{}: expected ${:02X}, got ${:02X}", name, expected, actual));
+            }
+        }
+
+        for entry in state["ram"].as_array().unwrap() {
+            let address = number(&entry[0]) as u16;
+            let expected = number(&entry[1]) as u8;
+            let actual = cpu.bus.memory.read8(address);
+            if actual != expected {
+                diffs.push(format!("ram[${:04X}]: expected ${:02X}, got ${:02X}", address, expected, actual));
+            }
+        }
+
+        let actual = &cpu.bus.cycles;
+        for (i, (expected, actual)) in cycles.iter().zip(actual).enumerate() {
+            if expected != actual {
+                diffs.push(format!("cycle {}: expected {}, got {}", i + 1, expected, actual));
+            }
+        }
+        if actual.len() != cycles.len() {
+            diffs.push(format!("cycles: expected {}, got {}", cycles.len(), actual.len()));
+        }
+        diffs
+    }
+
+    fn parse_cycles(test: &Value) -> Vec<Cycle> {
+        test["cycles"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|cycle| Cycle {
+                address: number(&cycle[0]) as u16,
+                data: number(&cycle[1]) as u8,
+                write: cycle[2] == "write",
+            })
+            .collect()
+    }
+
+    // Runs one vector and returns the mismatches
+    fn run_test(test: &Value, variant: Variant) -> Vec<String> {
+        let mut cpu = Cpu::with_bus(RecordingBus::default());
+        cpu.set_variant(variant);
+        set_state(&mut cpu, &test["initial"]);
+        cpu.step_instruction();
+        diff_state(&mut cpu, &test["final"], &parse_cycles(test))
+    }
+
+    // Runs a JAM vector, which must stop on the opcode
+    fn run_jam_test(test: &Value, variant: Variant) -> Vec<String> {
+        let mut cpu = Cpu::with_bus(RecordingBus::default());
+        cpu.set_variant(variant);
+        set_state(&mut cpu, &test["initial"]);
+        let pc = cpu.pc;
+        let opcode = cpu.bus.memory.read8(pc);
+        cpu.step_instruction();
+
+        let mut diffs = vec![];
+        if cpu.jammed() != Some(Jam { opcode, pc }) {
+            diffs.push(format!("jam: expected {:02X} at ${:04X}, got {:?}", opcode, pc, cpu.jammed()));
+        }
+        if cpu.pc != pc {
+            diffs.push(format!("pc: expected ${:04X}, got ${:04X}", pc, cpu.pc));
+        }
+        diffs
+    }
+
+    fn vector_dir() -> PathBuf {
+        match std::env::var("CPU_TEST_VECTORS") {
+            Ok(dir) => PathBuf::from(dir),
+            Err(_) => PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/vectors"),
+        }
+    }
+
+    #[test]
+    #[ignore = "needs the SingleStepTests vectors"]
+    fn test_single_step_vectors() {
+        let dir = vector_dir();
+        assert!(dir.is_dir(), "No CPU test vectors in {}", dir.display());
+        let variant = match std::env::var("CPU_TEST_VARIANT").as_deref() {
+            Ok("nmos") => Variant::Nmos6502,
+            _ => Variant::Ricoh2A03,
+        };
+
+        let mut reports = vec![];
+        let mut failed_opcodes = vec![];
+        let mut missing_opcodes = vec![];
+        let mut total = 0;
+        for opcode in 0..=0xffu8 {
+            let path = dir.join(format!("{:02x}.json", opcode));
+            // A partly copied set must not pass
+            let Ok(data) = std::fs::read(&path) else {
+                missing_opcodes.push(format!("{:02X}", opcode));
+                continue;
+            };
+            let tests: Vec<Value> = serde_json::from_slice(&data).expect("Invalid test vector");
+            let mut failed = 0;
+            for test in &tests {
+                total += 1;
+                let diffs = if JAM_OPCODES.contains(&opcode) {
+                    run_jam_test(test, variant)
+                } else {
+                    run_test(test, variant)
+                };
+                if diffs.is_empty() {
+                    continue;
+                }
+                failed += 1;
+                if reports.len() < MAX_REPORTS {
+                    let name = test["name"].as_str().unwrap_or_default();
+                    reports.push(format!("{}\n    {}", name, diffs.join("\n    ")));
+                }
+            }
+            if failed > 0 {
+                failed_opcodes.push(format!("{:02X}: {}/{}", opcode, failed, tests.len()));
+            }
+        }
+
+        assert!(
+            missing_opcodes.is_empty(),
+            "No test vectors for opcodes in {}: {}",
+            dir.display(),
+            missing_opcodes.join(", ")
+        );
+        assert!(total > 0, "No tests in the vectors in {}", dir.display());
+        assert!(
+            failed_opcodes.is_empty(),
+            "{}\nFailed opcodes of {} tests: {}",
+            reports.join("\n"),
+            total,
+            failed_opcodes.join(", ")
+        );
+    }
+
+    #[test]
+    fn test_single_step_harness() {
+        // LDA ($10),Y crossing a page
+        let mut test: Value = serde_json::from_str(
+            r#"{
+                "name": "b1 10 ff",
+                "initial": {"pc": 512, "s": 253, "a": 0, "x": 0, "y": 255, "p": 36,
+                    "ram": [[512, 177], [513, 16], [16, 1], [17, 3], [1024, 128]]},
+                "final": {"pc": 514, "s": 253, "a": 128, "x": 0, "y": 255, "p": 164,
+                    "ram": [[512, 177], [513, 16], [16, 1], [17, 3], [1024, 128]]},
+                "cycles": [[512, 177, "read"], [513, 16, "read"], [16, 1, "read"], [17, 3, "read"],
+                    [768, 0, "read"], [1024, 128, "read"]]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(run_test(&test, Variant::Ricoh2A03), Vec::<String>::new());
+
+        test["final"]["a"] = 0x7f.into();
+        test["cycles"][4][0] = 0x301.into();
+        test["cycles"].as_array_mut().unwrap().pop();
+        assert_eq!(
+            run_test(&test, Variant::Ricoh2A03),
+            vec![
+                "a: expected $7F, got $80",
+                "cycle 5: expected read $0301 = $00, got read $0300 = $00",
+                "cycles: expected 5, got 6",
+            ]
+        );
+
+        // JAM at $0200, the cycles of the vector are not compared
+        let test: Value = serde_json::from_str(
+            r#"{
+                "name": "02 00 00",
+                "initial": {"pc": 512, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[512, 2]]},
+                "final": {"pc": 513, "s": 253, "a": 0, "x": 0, "y": 0, "p": 36, "ram": [[512, 2]]},
+                "cycles": [[512, 2, "read"], [513, 0, "read"]]
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(run_jam_test(&test, Variant::Ricoh2A03), Vec::<String>::new());
+    }
+}
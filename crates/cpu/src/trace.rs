@@ -1,21 +1,11 @@
-use crate::{Cpu, opcode::{OPCODES, MODE2BYTES, AddressingMode, Opcodes}};
+use crate::{Cpu, CpuBus, opcode::{OPCODES, MODE2BYTES, AddressingMode, Opcodes}};
 
-impl Cpu {
+impl<M: CpuBus> Cpu<M> {
     pub fn trace(&mut self) -> String {
         let opcode_u8 = self.bus.read8_trace(self.pc);
-        if !OPCODES.contains_key(&opcode_u8) {
-            println!("Unknown opcode {:X}", opcode_u8);
-        }
         let (opcode, _, mode, is_official) = &OPCODES[&opcode_u8];
 
-        let status: u8 = u8::from(self.f.n) << 7
-            | u8::from(self.f.v) << 6
-            | u8::from(self.f.x) << 5
-            | u8::from(self.f.b) << 4
-            | u8::from(self.f.d) << 3
-            | u8::from(self.f.i) << 2
-            | u8::from(self.f.z) << 1
-            | u8::from(self.f.c) << 0;
+        let status = self.status();
 
         let mem_content = (0..(1 + MODE2BYTES[mode]))
             .map(|i| format!("{:02X}", self.bus.read8_trace(self.pc + i)))
@@ -137,7 +127,7 @@ impl Cpu {
             false => format!("*{:?}", opcode)
         };
 
-        let (ppu_cycles, ppu_scanlines) = self.bus.ppu.get_cycles_scanlines();
+        let (ppu_cycles, ppu_scanlines) = self.bus.ppu_position();
         format!(
             "{:04X}  {:8} {} {:27} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:>3},{:>3} CYC:{}",
             self.pc, mem_content, opcode, operands, self.a, self.x, self.y, status, self.sp,
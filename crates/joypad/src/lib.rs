@@ -48,6 +48,12 @@ impl Joypad {
         u8::from(result)
     }
 
+    // The shift register starts over, buttons held down stay pressed
+    pub fn reset(&mut self) {
+        self.strobe = false;
+        self.index = 0;
+    }
+
     pub fn write(&mut self, data: u8) {
         self.strobe = data == 1;
         if self.strobe {
@@ -2,16 +2,20 @@ mod registers;
 mod renderer;
 
 use core::panic;
+use std::cell::RefCell;
+use std::rc::Rc;
 
 use renderer::Renderer;
-use registers::{MaskRegister, StatusRegister, AddressRegister, ControlRegister, ScrollRegister};
-use cartridge::Mirroring;
+use registers::{MaskRegister, StatusRegister, ControlRegister};
+use cartridge::{Cartridge, Mirroring, Region};
 use sdl2::render::Texture;
 
 pub const WIDTH: usize = 256;
 pub const HEIGHT: usize = 240;
 
-#[derive(Default)]
+// The loopy registers, laid out as yyy NN YYYYY XXXXX
+// https://www.nesdev.org/wiki/PPU_scrolling
+#[derive(Default, Clone, Copy)]
 struct InternalRegister15 {
     coarse_x: u8,
     coarse_y: u8,
@@ -20,16 +24,79 @@ struct InternalRegister15 {
     fine_y: u8
 }
 
+impl InternalRegister15 {
+    fn get(&self) -> u16 {
+        (self.fine_y as u16) << 12
+            | (self.nametable_y as u16) << 11
+            | (self.nametable_x as u16) << 10
+            | (self.coarse_y as u16) << 5
+            | self.coarse_x as u16
+    }
+
+    fn set(&mut self, data: u16) {
+        self.coarse_x = (data & 0b1_1111) as u8;
+        self.coarse_y = (data >> 5 & 0b1_1111) as u8;
+        self.nametable_x = data & 0x400 > 0;
+        self.nametable_y = data & 0x800 > 0;
+        self.fine_y = (data >> 12 & 0b111) as u8;
+    }
+}
+
 pub struct Registers {
     mask: MaskRegister,
     stat: StatusRegister,
-    addr: AddressRegister,
     ctrl: ControlRegister,
-    scrl: ScrollRegister,
     oam_addr: u8,
     internal_t: InternalRegister15,
     internal_v: InternalRegister15,
-    // fine_x: u8
+    fine_x: u8,
+    // First or second write of PPUSCROLL and PPUADDR
+    w: bool,
+}
+
+// Tile fetched for the next 8 dots and the shift registers feeding the pixels
+#[derive(Default)]
+struct Background {
+    next_tile: u8,
+    next_attr: u8,
+    next_lo: u8,
+    next_hi: u8,
+    pattern_lo: u16,
+    pattern_hi: u16,
+    attr_lo: u16,
+    attr_hi: u16,
+}
+
+impl Background {
+    fn shift(&mut self) {
+        self.pattern_lo <<= 1;
+        self.pattern_hi <<= 1;
+        self.attr_lo <<= 1;
+        self.attr_hi <<= 1;
+    }
+
+    // The attribute applies to all 8 pixels of the tile
+    fn reload(&mut self) {
+        let fill = |bit: bool| if bit { 0xff } else { 0x00 };
+        self.pattern_lo = (self.pattern_lo & 0xff00) | self.next_lo as u16;
+        self.pattern_hi = (self.pattern_hi & 0xff00) | self.next_hi as u16;
+        self.attr_lo = (self.attr_lo & 0xff00) | fill(self.next_attr & 0b01 > 0);
+        self.attr_hi = (self.attr_hi & 0xff00) | fill(self.next_attr & 0b10 > 0);
+    }
+}
+
+// A sprite selected for the line, with its pattern row fetched during dots 257-320.
+// The size is kept from evaluation, since PPUCTRL may change before the fetch.
+#[derive(Clone, Copy)]
+struct SpriteUnit {
+    x: u8,
+    tile: u8,
+    attr: u8,
+    row: u8,
+    tall: bool,
+    lo: u8,
+    hi: u8,
+    sprite_0: bool,
 }
 
 #[derive(PartialEq, Eq)]
@@ -41,29 +108,38 @@ pub enum TickResult {
 }
 
 pub struct Ppu {
-    pub chr_rom: Vec<u8>,
+    pub cartridge: Rc<RefCell<Cartridge>>,
     pub palette_table: [u8; 32],
     pub vram: [u8; 2048],
     pub oam_data: [u8; 256],
     pub reg: Registers,
-    pub mirroring: Mirroring,
     data_fifo: u8, // temporary buffer for Data Register
+    a12: bool, // last level of PPU address line 12 seen by the cartridge
+    bg: Background,
+    // Sprites of the current line in priority order, at most 8 unless the limit is off
+    sprites: Vec<SpriteUnit>,
+    sprite_limit: bool,
 
     cycles: usize,
     scanlines: usize,
+    odd_frame: bool,
+    region: Region,
     fb: Renderer
 }
 
 impl Ppu {
     pub fn new() -> Self {
-        Ppu::load_cartridge(vec![0], Mirroring::Invalid)
+        Ppu::load_cartridge(Rc::new(RefCell::new(Cartridge::new())))
     }
 
     pub fn new_test_vertical() -> Self {
-        Ppu::load_cartridge(vec![0; 2048], Mirroring::Vertical)
+        let mut cartridge = Cartridge::new();
+        cartridge.chr_rom = vec![0; 2048];
+        cartridge.screen_mirroring = Mirroring::Vertical;
+        Ppu::load_cartridge(Rc::new(RefCell::new(cartridge)))
     }
 
-    pub fn load_cartridge(chr_rom: Vec<u8>, mirroring: Mirroring) -> Self {
+    pub fn load_cartridge(cartridge: Rc<RefCell<Cartridge>>) -> Self {
         Ppu {
             vram: [0; 2048],
             oam_data: [0; 64 * 4],
@@ -71,134 +147,100 @@ impl Ppu {
             reg: Registers {
                 mask: MaskRegister::from_bits_truncate(0),
                 stat: StatusRegister::from_bits_truncate(0),
-                addr: AddressRegister::new(),
                 ctrl: ControlRegister::new(),
-                scrl: ScrollRegister::new(),
                 oam_addr: 0,
                 internal_t: Default::default(),
                 internal_v: Default::default(),
-                // fine_x: 0,
+                fine_x: 0,
+                w: false,
             },
             data_fifo: 0,
-            chr_rom,
-            mirroring,
+            a12: false,
+            bg: Default::default(),
+            sprites: Vec::with_capacity(8),
+            sprite_limit: true,
+            cartridge,
             cycles: 21,
             scanlines: 0,
+            odd_frame: false,
+            region: Region::NTSC,
             fb: Renderer::new()
         }
     }
 
-    pub fn set_renderer_enabled(&mut self, enabled: bool) {
-        self.fb.set_enabled(enabled);
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
     }
 
-    pub fn get_cycles_scanlines(&self) -> (usize, usize) {
-        // this is for trace()
-        (self.cycles, self.scanlines)
+    // PPUCTRL, PPUMASK, the scroll and the write latch are cleared. VRAM, OAM, palettes
+    // and PPUADDR are kept.
+    // https://www.nesdev.org/wiki/PPU_power_up_state
+    pub fn reset(&mut self) {
+        self.write_ctrl(0);
+        self.write_mask(0);
+        self.reg.internal_t = Default::default();
+        self.reg.fine_x = 0;
+        self.reg.w = false;
+        self.data_fifo = 0;
     }
 
-    fn _sprite_0_hit(&self) -> bool {
-        let sprite_palettes = self.fb.get_palette(&self.palette_table, 0x10);
-
-        let sprite_tile_id = self.oam_data[1] as usize;
-        let sprite_attr = self.oam_data[2];
-        // let (scroll_x, scroll_y) = self.get_scroll_offset();
-        let sprite_y = self.oam_data[0] as usize;
-        let sprite_x = self.oam_data[3] as usize;
-
-        let get_bg_color_id = |x: usize, y: usize| -> usize {
-            let x = x % (WIDTH * 2);
-            let y = y % (HEIGHT * 2);
-
-            let x_8 = x / 8;
-            let y_8 = y / 8;
-
-            // todo support vertical scroll
-            let (vram, vram_offset) = match (x < WIDTH, y < HEIGHT) {
-                (true, true) => (&self.vram[0..0x400], y_8 * 32 + x_8),
-                (true, false) => todo!(),
-                (false, true) => (&self.vram[0x400..0x800], y_8 * 32 + x_8 - 32),
-                (false, false) => todo!(),
-            };
-
-            let tile_id = vram[vram_offset] as usize;
-            let chr_rom = &self.chr_rom[self.get_bg_chr_rom_range()];
-
-            let x_lsb3 = x & 0b111;
-            let y_lsb3 = y & 0b111;
-            let hi = chr_rom[tile_id * 16 + y_lsb3] as usize;
-            let lo = chr_rom[tile_id * 16 + y_lsb3 + 8] as usize;
-            let mask = 0b1000_0000 >> x_lsb3;
-            let color_index = (((lo & mask) << 1) | (hi & mask)) as usize >> (0b111 - x_lsb3);
-            let palette = self.fb.get_bg_tile_palette(x_8, y_8, vram, &self.palette_table);
-            palette[color_index]
-        };
-
-        let bg0_id = self.palette_table[0] as usize;
-
-        let sprite_palette = sprite_palettes[(sprite_attr & 0b11) as usize];
-        let sprite_flip_h = sprite_attr & 0b0100_0000 > 0;
-        let sprite_flip_v = sprite_attr & 0b1000_0000 > 0;
-        let sprite_chr_rom = &self.chr_rom[self.get_sprite_chr_rom_range()];
-        let sprite_tile = &sprite_chr_rom[sprite_tile_id*16..=sprite_tile_id*16+15];
-
-        for y in 0..=7 {
-            let y = if sprite_flip_h { 7 - y } else { y };
-            if self.scanlines != sprite_y + y {
-                continue;
-            }
-            let mut sprite_hi = sprite_tile[y];
-            let mut sprite_lo = sprite_tile[y + 8];
-            for x in (0..=7).rev() {
-                let sprite_color_index = ((sprite_lo & 1) << 1) | (sprite_hi & 1);
-                sprite_hi >>= 1;
-                sprite_lo >>= 1;
-
-                let x = if sprite_flip_v { 7 - x } else { x };
-                if self.cycles <= sprite_x + x {
-                    continue;
-                }
+    // Everything starts over. The CPU reset sequence then advances it by 21 dots.
+    pub fn power_cycle(&mut self) {
+        let region = self.region;
+        let sprite_limit = self.sprite_limit;
+        *self = Ppu::load_cartridge(self.cartridge.clone());
+        self.region = region;
+        self.sprite_limit = sprite_limit;
+        self.cycles = 0;
+    }
 
-                let sprite_color_id = sprite_palette[sprite_color_index as usize];
-                let bg_color_id = get_bg_color_id(sprite_x + x, sprite_y + y);
+    // 261 on NTSC, 311 on PAL and Dendy
+    fn pre_render_line(&self) -> usize {
+        self.region.scanlines_per_frame() - 1
+    }
 
-                if sprite_color_id != bg0_id && bg_color_id != bg0_id {
-                    return true;
-                }
-            }
+    // Dendy has 50 extra lines before vblank to keep NTSC vblank length
+    // https://www.nesdev.org/wiki/Cycle_reference_chart
+    fn vblank_line(&self) -> usize {
+        match self.region {
+            Region::Dendy => 291,
+            _ => 241,
         }
-
-        return false;
     }
 
-    fn sprite_0_hit_rough(&self) -> bool {
-        let sprite_y = self.oam_data[0] as usize;
-        let sprite_x = self.oam_data[3] as usize;
-        (sprite_y == self.scanlines as usize)
-        && sprite_x <= self.cycles
-        && self.reg.mask.contains(MaskRegister::SHOW_SPRITES)
+    pub fn set_renderer_enabled(&mut self, enabled: bool) {
+        self.fb.set_enabled(enabled);
     }
 
+    // Without the limit every sprite on a line is drawn and nothing flickers.
+    // The overflow flag still follows the hardware.
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
 
-    fn get_scroll_offset(&self) -> (usize, usize) {
-        let scroll = self.reg.scrl.get();
-        let mut x = scroll.0 as usize;
-        let mut y = scroll.1 as usize;
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
 
-        if self.reg.internal_v.nametable_x {
-            x += WIDTH;
-        }
-        if self.reg.internal_v.nametable_y {
-            y += HEIGHT;
-        }
+    pub fn get_cycles_scanlines(&self) -> (usize, usize) {
+        // this is for trace()
+        (self.cycles, self.scanlines)
+    }
 
-        (x, y)
+    fn sprite_height(&self) -> usize {
+        if self.reg.ctrl.contains(ControlRegister::SPRITE_SIZE) { 16 } else { 8 }
     }
 
-    fn get_sprite_chr_rom_range(&self) -> std::ops::Range<usize>   {
-        let sprite_pattern_addr = self.reg.ctrl & ControlRegister::SPRITE_PATTERN_ADDR;
-        let offset = 512 * sprite_pattern_addr.bits() as usize;
-        offset..offset+0x1000
+    // 8x16 sprites ignore PPUCTRL and take the pattern table from bit 0 of the tile
+    // https://www.nesdev.org/wiki/PPU_OAM#Byte_1
+    fn sprite_pattern_table(&self, tile: u8, tall: bool) -> u16 {
+        if tall {
+            (tile as u16 & 1) * 0x1000
+        } else if self.reg.ctrl.contains(ControlRegister::SPRITE_PATTERN_ADDR) {
+            0x1000
+        } else {
+            0
+        }
     }
 
     fn get_bg_chr_rom_range(&self) -> std::ops::Range<usize>   {
@@ -207,6 +249,23 @@ impl Ppu {
         offset..offset+0x1000
     }
 
+    fn rendering_enabled(&self) -> bool {
+        self.reg.mask.intersects(MaskRegister::SHOW_BG | MaskRegister::SHOW_SPRITES)
+    }
+
+    // Lines on which the PPU fetches and moves v
+    fn is_render_line(&self) -> bool {
+        self.scanlines <= 239 || self.scanlines == self.pre_render_line()
+    }
+
+    // Pattern and nametable fetches of the renderer
+    fn read_vram(&self, addr: u16) -> u8 {
+        match addr {
+            0..=0x1fff => self.cartridge.borrow().read_chr(addr),
+            _ => self.read_nametable(addr),
+        }
+    }
+
     fn increment_x(&mut self) {
         let v = &mut self.reg.internal_v;
         if v.coarse_x == 31 {
@@ -226,98 +285,268 @@ impl Ppu {
             if v.coarse_y == 29 {
                 v.coarse_y = 0;
                 v.nametable_y = !v.nametable_y;           
+            } else if v.coarse_y == 31 {
+                // Out of the nametable into the attributes, wraps without switching
+                v.coarse_y = 0;
             } else {
                 v.coarse_y += 1;
             }
         }
     }
 
-    fn tick_single(&mut self) -> TickResult {
-        let show_sprites = self.reg.mask.contains(MaskRegister::SHOW_SPRITES);
-        let show_bg = self.reg.mask.contains(MaskRegister::SHOW_BG);
-        if (show_sprites || show_bg) && (0..=239).contains(&self.scanlines) {
-            if show_sprites && !self.reg.stat.contains(StatusRegister::SPRITE_0_HIT) && self.sprite_0_hit_rough() {
-                self.reg.stat.set(StatusRegister::SPRITE_0_HIT, true);
+    // Pattern fetches switch A12 between the bg and sprite tables during rendering.
+    // Nametable fetches in between are too short for MMC3's filter to notice,
+    // so only the pattern table of the current fetch phase is considered.
+    fn update_a12(&mut self) {
+        let pattern_table = match self.cycles {
+            257..=320 => {
+                // Empty slots fetch tile $FF
+                let (tile, tall) = self.sprites.get((self.cycles - 257) / 8)
+                    .map_or((0xff, self.sprite_height() == 16), |sprite| (sprite.tile, sprite.tall));
+                self.sprite_pattern_table(tile, tall) as usize
             }
+            _ => self.get_bg_chr_rom_range().start,
+        };
+        let a12 = pattern_table & 0x1000 > 0;
+        if a12 && !self.a12 {
+            self.cartridge.borrow_mut().on_a12_rise();
+        }
+        self.a12 = a12;
+    }
 
-            if (1..=256).contains(&self.cycles) || (327..).contains(&self.cycles) {
-                if self.cycles & 0b111 == 0b111 && self.cycles != 255 {
-                    self.increment_x();
-                }
-                if self.cycles == 256 {
-                    self.increment_y();
+    // Fetches and scroll updates of one dot on a render line
+    // https://www.nesdev.org/wiki/PPU_rendering
+    fn render_dot(&mut self) {
+        let dot = self.cycles;
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.bg.shift();
+            self.fetch_background();
+        }
+        if dot == 256 {
+            self.increment_y();
+        }
+        if dot == 257 {
+            // copy x
+            let v = &mut self.reg.internal_v;
+            let t = &mut self.reg.internal_t;
+            v.nametable_x = t.nametable_x;
+            v.coarse_x = t.coarse_x;
+            self.evaluate_sprites();
+        }
+        if (257..=320).contains(&dot) {
+            self.fetch_sprite();
+        }
+        if self.scanlines == self.pre_render_line() && (280..=304).contains(&dot) {
+            // copy y
+            let v = &mut self.reg.internal_v;
+            let t = &mut self.reg.internal_t;
+            v.nametable_y = t.nametable_y;
+            v.coarse_y = t.coarse_y;
+            v.fine_y = t.fine_y;
+        }
+    }
+
+    // Every tile takes 8 dots: nametable, attribute, then the two pattern planes
+    fn fetch_background(&mut self) {
+        let v = self.reg.internal_v;
+        let pattern_addr = |ppu: &Ppu| {
+            let table = if ppu.reg.ctrl.contains(ControlRegister::BACKROUND_PATTERN_ADDR) { 0x1000 } else { 0 };
+            table + ppu.bg.next_tile as u16 * 16 + v.fine_y as u16
+        };
+        match (self.cycles - 1) % 8 {
+            0 => {
+                self.bg.reload();
+                self.bg.next_tile = self.read_vram(0x2000 | (v.get() & 0x0fff));
+            }
+            2 => {
+                let v = v.get();
+                let attr = self.read_vram(0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07));
+                // 2 bits for each 2x2 tiles
+                let shift = (self.reg.internal_v.coarse_y & 0b10) << 1 | (self.reg.internal_v.coarse_x & 0b10);
+                self.bg.next_attr = (attr >> shift) & 0b11;
+            }
+            4 => self.bg.next_lo = self.read_vram(pattern_addr(self)),
+            6 => self.bg.next_hi = self.read_vram(pattern_addr(self) + 8),
+            7 => self.increment_x(),
+            _ => {}
+        }
+    }
+
+    // Copies the sprites of the next line to secondary OAM, 8 at most. The pre-render
+    // line finds none. Runs over dots 65-256 on hardware, done at once here.
+    // https://www.nesdev.org/wiki/PPU_sprite_evaluation
+    fn evaluate_sprites(&mut self) {
+        self.sprites.clear();
+        if self.scanlines == self.pre_render_line() {
+            return;
+        }
+        let line = self.scanlines;
+        let height = self.sprite_height();
+        let in_range = |y: u8| line.wrapping_sub(y as usize) < height;
+        let unit = |sprite: &[u8], sprite_0: bool| SpriteUnit {
+            x: sprite[3],
+            tile: sprite[1],
+            attr: sprite[2],
+            row: line.wrapping_sub(sprite[0] as usize) as u8,
+            tall: height == 16,
+            lo: 0,
+            hi: 0,
+            sprite_0,
+        };
+
+        let mut n = 0;
+        while n < 64 && self.sprites.len() < 8 {
+            let sprite = &self.oam_data[n * 4..n * 4 + 4];
+            if in_range(sprite[0]) {
+                self.sprites.push(unit(sprite, n == 0));
+            }
+            n += 1;
+        }
+
+        // Once secondary OAM is full, a miss increments m along with n, so the bytes
+        // compared are tile, attribute or X instead of Y
+        let rest = n;
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam_data[n * 4 + m]) {
+                self.reg.stat.insert(StatusRegister::SPRITE_OVERFLOW);
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 0b11;
+        }
+
+        if !self.sprite_limit {
+            for sprite in self.oam_data[rest * 4..].chunks(4) {
+                if in_range(sprite[0]) {
+                    self.sprites.push(unit(sprite, false));
                 }
-            } else if self.cycles == 257 {
-                // copy x
-                let v = &mut self.reg.internal_v;
-                let t = &mut self.reg.internal_t;
-                v.nametable_x = t.nametable_x;
-                v.coarse_x = t.coarse_x;
             }
-        } else if self.scanlines == 261 {
-            if (1..=256).contains(&self.cycles) || (328..).contains(&self.cycles) {
-                if self.cycles & 0b111 == 0b111 && self.cycles != 255 {
-                    self.increment_x();
+        }
+    }
+
+    // Pattern row of a sprite, flipped so that the leftmost pixel is bit 7.
+    // 8x16 sprites are a pair of tiles, and flipping them vertically swaps the two.
+    fn fetch_sprite_plane(&self, sprite: &SpriteUnit, plane: u16) -> u8 {
+        let height = if sprite.tall { 16 } else { 8 };
+        let table = self.sprite_pattern_table(sprite.tile, sprite.tall);
+        let row = if sprite.attr & 0b1000_0000 > 0 { height - 1 - sprite.row } else { sprite.row };
+        let tile = if sprite.tall { (sprite.tile & 0xfe) + row / 8 } else { sprite.tile };
+        let data = self.read_vram(table + tile as u16 * 16 + (row & 0b111) as u16 + plane);
+        if sprite.attr & 0b0100_0000 > 0 { data.reverse_bits() } else { data }
+    }
+
+    // 8 dots for each sprite, with the pattern planes in the same slots as the background.
+    // Sprites over the limit have no slot and are fetched at the end.
+    fn fetch_sprite(&mut self) {
+        let slot = (self.cycles - 257) / 8;
+        if self.cycles == 320 {
+            for slot in 8..self.sprites.len() {
+                let sprite = self.sprites[slot];
+                self.sprites[slot].lo = self.fetch_sprite_plane(&sprite, 0);
+                self.sprites[slot].hi = self.fetch_sprite_plane(&sprite, 8);
+            }
+        }
+        let Some(sprite) = self.sprites.get(slot).copied() else {
+            return;
+        };
+        match (self.cycles - 257) % 8 {
+            4 => self.sprites[slot].lo = self.fetch_sprite_plane(&sprite, 0),
+            6 => self.sprites[slot].hi = self.fetch_sprite_plane(&sprite, 8),
+            _ => {}
+        }
+    }
+
+    // Pixel and palette of the background at x, 0 when transparent
+    fn bg_pixel(&self, x: usize) -> (u8, u8) {
+        if !self.reg.mask.contains(MaskRegister::SHOW_BG)
+            || (x < 8 && !self.reg.mask.contains(MaskRegister::SHOW_LEFTMOST_BG)) {
+            return (0, 0);
+        }
+        let bit = 0x8000 >> self.reg.fine_x;
+        let plane = |shifter: u16| u8::from(shifter & bit > 0);
+        (
+            plane(self.bg.pattern_hi) << 1 | plane(self.bg.pattern_lo),
+            plane(self.bg.attr_hi) << 1 | plane(self.bg.attr_lo),
+        )
+    }
+
+    // Pixel of the frontmost opaque sprite at x, and the sprite
+    fn sprite_pixel(&self, x: usize) -> Option<(u8, SpriteUnit)> {
+        if !self.reg.mask.contains(MaskRegister::SHOW_SPRITES)
+            || (x < 8 && !self.reg.mask.contains(MaskRegister::SHOW_LEFTMOST_SPRITES)) {
+            return None;
+        }
+        self.sprites.iter().find_map(|sprite| {
+            let column = x.wrapping_sub(sprite.x as usize);
+            if column >= 8 {
+                return None;
+            }
+            let shift = 7 - column;
+            let pixel = (sprite.hi >> shift & 1) << 1 | (sprite.lo >> shift & 1);
+            (pixel != 0).then_some((pixel, *sprite))
+        })
+    }
+
+    // Outputs the pixel of the current dot
+    // https://www.nesdev.org/wiki/PPU_rendering#Preface
+    fn render_pixel(&mut self) {
+        let x = self.cycles - 1;
+        let color = if self.rendering_enabled() {
+            let (bg, bg_palette) = self.bg_pixel(x);
+            let sprite = self.sprite_pixel(x);
+            // Opaque pixels of sprite 0 and the background at the same dot, except at x=255.
+            // Clipping and disabled layers give transparent pixels, which never hit.
+            // https://www.nesdev.org/wiki/PPU_OAM#Sprite_zero_hits
+            if let Some((_, SpriteUnit { sprite_0: true, .. })) = sprite {
+                if bg != 0 && x != 255 {
+                    self.reg.stat.insert(StatusRegister::SPRITE_0_HIT);
                 }
-                if self.cycles == 256 {
-                    self.increment_y();
+            }
+            let palette_index = match sprite {
+                Some((pixel, sprite)) if bg == 0 || sprite.attr & 0b0010_0000 == 0 => {
+                    0x10 | (sprite.attr & 0b11) << 2 | pixel
                 }
-            } else if self.cycles == 257 {
-                // copy x
-                let v = &mut self.reg.internal_v;
-                let t = &mut self.reg.internal_t;
-                v.nametable_x = t.nametable_x;
-                v.coarse_x = t.coarse_x;
-            } else if (280..=340).contains(&self.cycles) {
-                let v = &mut self.reg.internal_v;
-                let t = &mut self.reg.internal_t;
-                v.nametable_y = t.nametable_y;
-                v.coarse_y = t.coarse_y;
-                v.fine_y = t.fine_y;
+                _ if bg == 0 => 0,
+                _ => bg_palette << 2 | bg,
+            };
+            self.palette_table[palette_index as usize]
+        } else {
+            self.palette_table[0]
+        };
+        let color = if self.reg.mask.contains(MaskRegister::GRAYSCALE) { color & 0x30 } else { color };
+        self.fb.set_pixel(x, self.scanlines, color);
+    }
+
+    fn tick_single(&mut self) -> TickResult {
+        let rendering = self.rendering_enabled();
+        if self.scanlines == self.pre_render_line() && self.cycles == 1 {
+            self.reg.stat.remove(StatusRegister::SPRITE_0_HIT | StatusRegister::SPRITE_OVERFLOW);
+        }
+        if rendering && self.is_render_line() {
+            if self.cycles > 0 {
+                self.update_a12();
             }
+            self.render_dot();
+        }
+        if self.scanlines <= 239 && (1..=256).contains(&self.cycles) {
+            self.render_pixel();
         }
 
         self.cycles += 1;
 
+        // The last dot of the pre-render line is skipped on odd NTSC frames
+        if self.cycles == 340 && self.odd_frame && rendering
+            && self.scanlines == self.pre_render_line() && self.region == Region::NTSC {
+            self.cycles += 1;
+        }
+
         if self.cycles == 341 {
 
             self.scanlines += 1;
             self.cycles -= 341;
 
-            // Render background every 8 lines except 0
-            let show_bg = self.reg.mask.contains(MaskRegister::SHOW_BG);
-            if show_bg && (1..=240).contains(&self.scanlines) && self.scanlines & 0b111 == 0 {
-                let row_number = (self.scanlines - 1) / 8;
-                let range = self.get_bg_chr_rom_range();
-                let chr_rom_slice = &self.chr_rom[range];
-
-                // Draw two screens(rows) for games using PPU scroll
-                let vram_slice_a = &self.vram[0..0x400];
-                let vram_slice_b = &self.vram[0x400..0x800];
-                self.fb.render_bg_row(row_number, 0, 0, chr_rom_slice, &self.palette_table, vram_slice_a);
-                match self.mirroring {
-                    Mirroring::Invalid => todo!(),
-                    Mirroring::Vertical => {
-                        self.fb.render_bg_row(row_number, WIDTH, 0, chr_rom_slice, &self.palette_table, vram_slice_b);
-                    },
-                    Mirroring::Horizontal => {
-                        self.fb.render_bg_row(row_number, 0, HEIGHT, chr_rom_slice, &self.palette_table, vram_slice_b);
-                    },
-                    Mirroring::FourScreen => todo!(),
-                }
-
-                // Copy current bg into viewport based on the value of scroll register and base nametable
-                let (x, y) = self.get_scroll_offset();
-                self.fb.copy_to_viewport(row_number, x, y);
-            }
-
-            // Render sprites at the end of visible scanlines
-            if self.scanlines == 241 {
-                if show_sprites {
-                    let range = self.get_sprite_chr_rom_range();
-                    self.fb.render_sprites(&self.chr_rom[range], &self.palette_table, &self.oam_data);
-                }
-
+            if self.scanlines == self.vblank_line() {
                 self.reg.stat.set(StatusRegister::VBLANK_STARTED, true);
                 // self.reg.stat.set(StatusRegister::SPRITE_0_HIT, false);
                 if self.reg.ctrl.enable_generage_nmi() {
@@ -328,10 +557,10 @@ impl Ppu {
             }
 
             // Start over
-            if self.scanlines == 262 {
+            if self.scanlines == self.region.scanlines_per_frame() {
                 self.scanlines = 0;
+                self.odd_frame = !self.odd_frame;
                 self.reg.stat.set(StatusRegister::VBLANK_STARTED, false);
-                self.reg.stat.set(StatusRegister::SPRITE_0_HIT, false);
                 return TickResult::ScanlineReset;
             }
         }
@@ -339,25 +568,32 @@ impl Ppu {
         return TickResult::None;
     }
 
-    pub fn tick(&mut self, cycles: u8) -> Vec<TickResult> {
-        (0..cycles).map(|_| self.tick_single()).collect()
+    pub fn tick(&mut self, dots: usize) -> Vec<TickResult> {
+        (0..dots).map(|_| self.tick_single()).collect()
     }
 
     fn is_vblank(&self) -> bool {
         !(0..=239).contains(&self.scanlines)
     }
 
+    // Accessing PPUDATA while rendering bumps both scroll counters instead
+    // https://www.nesdev.org/wiki/PPU_scrolling#$2007_reads_and_writes
     fn increment_vram_addr(&mut self) {
-        let amount = self.reg.ctrl.vram_increment_amount();
-        self.reg.addr.increment(amount);
+        if self.rendering_enabled() && self.is_render_line() {
+            self.increment_x();
+            self.increment_y();
+        } else {
+            let amount = self.reg.ctrl.vram_increment_amount() as u16;
+            let v = self.reg.internal_v.get().wrapping_add(amount);
+            self.reg.internal_v.set(v & 0x7fff);
+        }
     }
 
     pub fn read_stat(&mut self, trace: bool) -> u8 {
         let result = self.reg.stat.bits();
         if !trace {
             self.reg.stat &= !StatusRegister::VBLANK_STARTED;
-            self.reg.scrl.reset_latch();
-            self.reg.addr.reset_latch();
+            self.reg.w = false;
         }
         result
     }
@@ -367,7 +603,7 @@ impl Ppu {
     }
 
     pub fn read_data(&mut self, trace: bool) -> u8 {
-        let addr = self.reg.addr.get();
+        let addr = self.reg.internal_v.get() & 0x3fff;
         if !trace {
             self.increment_vram_addr();
         }
@@ -378,17 +614,16 @@ impl Ppu {
                     self.data_fifo
                 } else {
                     let result = self.data_fifo;
-                    self.data_fifo = self.chr_rom[addr as usize];
+                    self.data_fifo = self.cartridge.borrow().read_chr(addr);
                     result
                 }
             }
             0x2000..=0x3eff => {
-                let mirror_addr = self.get_mirror_addr(addr);
                 if trace {
                     self.data_fifo
                 } else {
                     let result = self.data_fifo;
-                    self.data_fifo = self.vram[mirror_addr as usize];
+                    self.data_fifo = self.read_nametable(addr);
                     result
                 }
             }
@@ -406,10 +641,6 @@ impl Ppu {
 
     pub fn write_mask(&mut self, data: u8) {
         self.reg.mask = MaskRegister::from_bits_truncate(data);
-
-        let bg = self.reg.mask.contains(MaskRegister::SHOW_LEFTMOST_BG);
-        let sprite = self.reg.mask.contains(MaskRegister::SHOW_LEFTMOST_SPRITES);
-        self.fb.set_draw_leftmost(bg, sprite);
     }
 
     pub fn write_oam_addr(&mut self, data: u8) {
@@ -422,7 +653,15 @@ impl Ppu {
     }
 
     pub fn write_scrl(&mut self, data: u8) {
-        self.reg.scrl.update(data);
+        let t = &mut self.reg.internal_t;
+        if !self.reg.w {
+            t.coarse_x = data >> 3;
+            self.reg.fine_x = data & 0b111;
+        } else {
+            t.coarse_y = data >> 3;
+            t.fine_y = data & 0b111;
+        }
+        self.reg.w = !self.reg.w;
     }
 
     pub fn write_ctrl(&mut self, data: u8) -> TickResult {
@@ -440,19 +679,25 @@ impl Ppu {
         }
     }
 
+    // The high byte goes to t first, and v only changes on the second write
     pub fn write_addr(&mut self, data: u8) {
-        self.reg.addr.update(data);
+        let t = self.reg.internal_t.get();
+        if !self.reg.w {
+            self.reg.internal_t.set((t & 0x00ff) | ((data as u16 & 0x3f) << 8));
+        } else {
+            self.reg.internal_t.set((t & 0xff00) | data as u16);
+            self.reg.internal_v = self.reg.internal_t;
+        }
+        self.reg.w = !self.reg.w;
     }
 
     pub fn write_data(&mut self, data: u8) {
-        let addr = self.reg.addr.get();
+        let addr = self.reg.internal_v.get() & 0x3fff;
         self.increment_vram_addr();
 
         match addr {
-            0x2000..=0x3eff => {
-                let mirror_addr = self.get_mirror_addr(addr);
-                self.vram[mirror_addr] = data;
-            }
+            0..=0x1fff => self.cartridge.borrow_mut().write_chr(addr, data),
+            0x2000..=0x3eff => self.write_nametable(addr, data),
             0x3f00..=0x3fff => {
                 let mirror_addr = addr & 0b0011_1111_0001_1111;
                 let palette_addr = (mirror_addr - 0x3f00) as usize;
@@ -476,7 +721,7 @@ impl Ppu {
         // 0x0800 to 0x0cff -> index = 2, etc
         let index = vram_addr / 0x400;
 
-        match (&self.mirroring, index) {
+        match (self.cartridge.borrow().mirroring(), index) {
             (Mirroring::Vertical, 0) => vram_addr,
             (Mirroring::Vertical, 1) => vram_addr,
             (Mirroring::Vertical, 2) => vram_addr - 0x800,
@@ -485,10 +730,31 @@ impl Ppu {
             (Mirroring::Horizontal, 1) => vram_addr - 0x400,
             (Mirroring::Horizontal, 2) => vram_addr - 0x400,
             (Mirroring::Horizontal, 3) => vram_addr - 0x800,
+            (Mirroring::SingleScreenA, _) => vram_addr & 0x3ff,
+            (Mirroring::SingleScreenB, _) => 0x400 | (vram_addr & 0x3ff),
+            // Past the end of vram, see read_nametable()
+            (Mirroring::FourScreen, _) => vram_addr,
             _ => panic!(),
         }
     }
 
+    // The PPU has 2KB for two nametables. Four-screen boards bring another 2KB
+    // for the other two.
+    // https://www.nesdev.org/wiki/Mirroring#4-Screen
+    fn read_nametable(&self, addr: u16) -> u8 {
+        match self.get_mirror_addr(addr) {
+            mirror_addr if mirror_addr < self.vram.len() => self.vram[mirror_addr],
+            mirror_addr => self.cartridge.borrow().read_vram(mirror_addr as u16),
+        }
+    }
+
+    fn write_nametable(&mut self, addr: u16, data: u8) {
+        match self.get_mirror_addr(addr) {
+            mirror_addr if mirror_addr < self.vram.len() => self.vram[mirror_addr] = data,
+            mirror_addr => self.cartridge.borrow_mut().write_vram(mirror_addr as u16, data),
+        }
+    }
+
     pub fn update_sdl_texture(&self, texture: &mut Texture) {
         texture.update(None, &self.fb.get_buffer(), WIDTH * 3).unwrap();
     }
@@ -499,6 +765,14 @@ impl Ppu {
 pub mod test {
     use super::*;
 
+    // A PPU with a blank iNES image, CHR-RAM when there are no CHR banks
+    fn ines_ppu(prg_banks: u8, chr_banks: u8, flags6: u8) -> Ppu {
+        let mut raw = vec![b'N', b'E', b'S', 0x1a, prg_banks, chr_banks, flags6, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        raw.resize(16 + prg_banks as usize * 0x4000 + chr_banks as usize * 0x2000, 0);
+        let cartridge = Cartridge::load(&raw).unwrap();
+        Ppu::load_cartridge(Rc::new(RefCell::new(cartridge)))
+    }
+
     #[test]
     fn test_ppu_vram_writes() {
         let mut ppu = Ppu::new_test_vertical();
@@ -519,7 +793,7 @@ pub mod test {
         ppu.write_addr(0x05);
 
         ppu.read_data(false); //load_into_buffer
-        assert_eq!(ppu.reg.addr.get(), 0x2306);
+        assert_eq!(ppu.reg.internal_v.get(), 0x2306);
         assert_eq!(ppu.read_data(false), 0x66);
     }
 
@@ -614,6 +888,127 @@ pub mod test {
         assert_eq!(ppu.read_data(false), 0x77); //read from B
     }
 
+    #[test]
+    fn test_vram_single_screen_mirror() {
+        let mut ppu = Ppu::new_test_vertical();
+        ppu.cartridge.borrow_mut().screen_mirroring = Mirroring::SingleScreenB;
+
+        ppu.write_addr(0x2C);
+        ppu.write_addr(0x05);
+
+        ppu.write_data(0x66); //write to the upper bank
+
+        assert_eq!(ppu.vram[0x0405], 0x66);
+
+        ppu.write_addr(0x20);
+        ppu.write_addr(0x05);
+
+        ppu.read_data(false); //load into buffer
+        assert_eq!(ppu.read_data(false), 0x66);
+    }
+
+    fn write_vram_at(ppu: &mut Ppu, addr: u16, data: u8) {
+        ppu.write_addr((addr >> 8) as u8);
+        ppu.write_addr(addr as u8);
+        ppu.write_data(data);
+    }
+
+    fn read_vram_at(ppu: &mut Ppu, addr: u16) -> u8 {
+        ppu.write_addr((addr >> 8) as u8);
+        ppu.write_addr(addr as u8);
+        ppu.read_data(false); //load into buffer
+        ppu.read_data(false)
+    }
+
+    // 4-Screen: https://www.nesdev.org/wiki/Mirroring#4-Screen
+    //   [0x2000 A ] [0x2400 B ]
+    //   [0x2800 C ] [0x2C00 D ]
+    #[test]
+    fn test_vram_four_screen_mirror() {
+        let mut ppu = ines_ppu(1, 1, 0x08);
+
+        for (addr, data) in [(0x2005, 0x11), (0x2405, 0x22), (0x2805, 0x33), (0x2c05, 0x44)] {
+            write_vram_at(&mut ppu, addr, data);
+        }
+        assert_eq!(ppu.vram[0x0005], 0x11);
+        assert_eq!(ppu.vram[0x0405], 0x22);
+        assert_eq!(ppu.cartridge.borrow().vram[0x0005], 0x33);
+        assert_eq!(ppu.cartridge.borrow().vram[0x0405], 0x44);
+
+        assert_eq!(read_vram_at(&mut ppu, 0x2805), 0x33);
+        assert_eq!(read_vram_at(&mut ppu, 0x3c05), 0x44);
+        assert_eq!(read_vram_at(&mut ppu, 0x2005), 0x11);
+    }
+
+    #[test]
+    fn test_vram_mirroring_switched_by_mapper() {
+        // AxROM starts on the lower nametable and switches with bit 4
+        let mut ppu = ines_ppu(2, 0, 0x70);
+
+        write_vram_at(&mut ppu, 0x2c05, 0x66);
+        assert_eq!(ppu.vram[0x0005], 0x66);
+
+        ppu.cartridge.borrow_mut().write_prg(0x8000, 0b1_0000);
+        write_vram_at(&mut ppu, 0x2005, 0x77);
+        assert_eq!(ppu.vram[0x0405], 0x77);
+        assert_eq!(read_vram_at(&mut ppu, 0x2805), 0x77);
+    }
+
+    #[test]
+    fn test_a12_rise_clocks_mmc3_once_per_scanline() {
+        let mut ppu = ines_ppu(2, 1, 0x40);
+
+        // IRQ after 4 scanlines
+        ppu.cartridge.borrow_mut().write_prg(0xc000, 3);
+        ppu.cartridge.borrow_mut().write_prg(0xc001, 0);
+        ppu.cartridge.borrow_mut().write_prg(0xe001, 0);
+
+        // Background from $0000, sprites from $1000
+        ppu.write_ctrl(0b0000_1000);
+        ppu.write_mask(0b0001_1000);
+
+        // The first rise reloads the counter
+        ppu.tick(255);
+        for _ in 0..2 {
+            ppu.tick(255);
+            ppu.tick(86);
+            assert!(!ppu.cartridge.borrow().irq());
+        }
+        ppu.tick(255);
+        ppu.tick(86);
+        assert!(ppu.cartridge.borrow().irq());
+    }
+
+    #[test]
+    fn test_frame_length_per_region() {
+        for (region, vblank_line) in [(Region::NTSC, 241), (Region::PAL, 241), (Region::Dendy, 291)] {
+            let mut ppu = Ppu::new();
+            ppu.set_region(region);
+            ppu.write_ctrl(0b1000_0000);
+
+            let results = ppu.tick(341 * region.scanlines_per_frame() - 21);
+            let nmi = results
+                .iter()
+                .position(|result| *result == TickResult::ShouldInterruptNmiAndUpdateTexture);
+            assert_eq!(nmi, Some(341 * vblank_line - 21 - 1));
+            assert!(results.last() == Some(&TickResult::ScanlineReset));
+        }
+    }
+
+    #[test]
+    fn test_chr_ram_writes() {
+        let mut ppu = ines_ppu(1, 0, 0);
+
+        ppu.write_addr(0x10);
+        ppu.write_addr(0x05);
+        ppu.write_data(0x66);
+
+        ppu.write_addr(0x10);
+        ppu.write_addr(0x05);
+        ppu.read_data(false); //load into buffer
+        assert_eq!(ppu.read_data(false), 0x66);
+    }
+
     #[test]
     fn test_read_stat_resets_latch() {
         let mut ppu = Ppu::new_test_vertical();
@@ -660,6 +1055,266 @@ pub mod test {
         assert_eq!(ppu.reg.stat.bits() >> 7, 0);
     }
 
+    // https://www.nesdev.org/wiki/PPU_scrolling#Summary
+    #[test]
+    fn test_loopy_registers() {
+        let mut ppu = Ppu::new_test_vertical();
+        ppu.write_ctrl(0b10);
+        ppu.write_scrl(0x7d);
+        assert_eq!(ppu.reg.internal_t.get(), 0x080f);
+        assert_eq!(ppu.reg.fine_x, 5);
+        ppu.write_scrl(0x5e);
+        assert_eq!(ppu.reg.internal_t.get(), 0x696f);
+
+        ppu.write_addr(0x3d);
+        assert_eq!(ppu.reg.internal_t.get(), 0x3d6f);
+        assert_eq!(ppu.reg.internal_v.get(), 0x0000);
+        ppu.write_addr(0xf0);
+        assert_eq!(ppu.reg.internal_t.get(), 0x3df0);
+        assert_eq!(ppu.reg.internal_v.get(), 0x3df0);
+        assert_eq!(ppu.reg.fine_x, 5);
+    }
+
+    fn pixel(ppu: &Ppu, x: usize, y: usize) -> (u8, u8, u8) {
+        let offset = (y * WIDTH + x) * 3;
+        let fb = ppu.fb.get_buffer();
+        (fb[offset], fb[offset + 1], fb[offset + 2])
+    }
+
+    #[test]
+    fn test_render_scroll_and_sprite() {
+        let mut ppu = ines_ppu(1, 0, 0);
+
+        // Tile 1 is solid color 1
+        ppu.write_addr(0x00);
+        ppu.write_addr(0x10);
+        for _ in 0..8 {
+            ppu.write_data(0xff);
+        }
+        // Tile 1 down the first column of the nametable
+        ppu.write_ctrl(0b100);
+        ppu.write_addr(0x20);
+        ppu.write_addr(0x00);
+        for _ in 0..30 {
+            ppu.write_data(1);
+        }
+        ppu.write_addr(0x3f);
+        ppu.write_addr(0x00);
+        ppu.write_ctrl(0);
+        for color in [0x0f, 0x30] {
+            ppu.write_data(color);
+        }
+        ppu.write_addr(0x3f);
+        ppu.write_addr(0x11);
+        ppu.write_data(0x16);
+        ppu.oam_data[0..4].copy_from_slice(&[20, 1, 0, 16]);
+
+        ppu.read_stat(false);
+        ppu.write_ctrl(0);
+        ppu.write_scrl(0);
+        ppu.write_scrl(0);
+        ppu.write_mask(0b0001_1110);
+
+        // The first frame starts mid-way, the scroll is in place from the pre-render line
+        while ppu.tick_single() != TickResult::ScanlineReset {}
+        ppu.tick(341 * 120 + 100);
+        ppu.write_scrl(8);
+        ppu.write_scrl(0);
+        while ppu.tick_single() != TickResult::ShouldUpdateTexture {}
+
+        let white = (0xff, 0xff, 0xff);
+        let black = (0x05, 0x05, 0x05);
+        assert_eq!(pixel(&ppu, 0, 10), white);
+        assert_eq!(pixel(&ppu, 7, 10), white);
+        assert_eq!(pixel(&ppu, 8, 10), black);
+
+        // Sprites show up one line below their Y
+        assert_eq!(pixel(&ppu, 16, 20), black);
+        assert_eq!(pixel(&ppu, 16, 21), (0xff, 0x22, 0x00));
+        assert_eq!(pixel(&ppu, 23, 28), (0xff, 0x22, 0x00));
+        assert_eq!(pixel(&ppu, 24, 21), black);
+
+        // Scrolled by a tile from line 121, wrapping into the next nametable
+        assert_eq!(pixel(&ppu, 0, 120), white);
+        assert_eq!(pixel(&ppu, 0, 200), black);
+        assert_eq!(pixel(&ppu, 247, 200), black);
+        assert_eq!(pixel(&ppu, 248, 200), white);
+        assert_eq!(pixel(&ppu, 255, 200), white);
+    }
+
+    fn overflow(ppu: &Ppu) -> bool {
+        ppu.reg.stat.contains(StatusRegister::SPRITE_OVERFLOW)
+    }
+
+    #[test]
+    fn test_sprite_evaluation_limit_and_overflow() {
+        let mut ppu = Ppu::new_test_vertical();
+        // Everything off the screen, except 9 sprites on lines 30-37
+        ppu.oam_data.fill(0xff);
+        for n in 0..9 {
+            ppu.oam_data[n * 4..n * 4 + 4].copy_from_slice(&[30, 0, 0, n as u8 * 8]);
+        }
+
+        ppu.scanlines = 29;
+        ppu.evaluate_sprites();
+        assert!(ppu.sprites.is_empty());
+        assert!(!overflow(&ppu));
+
+        ppu.scanlines = 30;
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.sprites.len(), 8);
+        assert_eq!(ppu.sprites[7].x, 56);
+        assert!(overflow(&ppu));
+
+        // The limit toggle draws the 9th one and leaves the flag alone
+        ppu.reg.stat.remove(StatusRegister::SPRITE_OVERFLOW);
+        ppu.set_sprite_limit(false);
+        ppu.scanlines = 37;
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.sprites.len(), 9);
+        assert_eq!(ppu.sprites[8].x, 64);
+        assert_eq!(ppu.sprites[8].row, 7);
+        assert!(overflow(&ppu));
+    }
+
+    // https://www.nesdev.org/wiki/PPU_sprite_evaluation#Sprite_overflow_bug
+    #[test]
+    fn test_sprite_overflow_bug() {
+        let mut ppu = Ppu::new_test_vertical();
+        ppu.oam_data.fill(0xff);
+        for n in 0..8 {
+            ppu.oam_data[n * 4..n * 4 + 4].copy_from_slice(&[30, 0, 0, 0]);
+        }
+        ppu.scanlines = 30;
+
+        // The 10th sprite is on the line, but its tile number is compared instead of Y
+        ppu.oam_data[36..40].copy_from_slice(&[30, 0xff, 0xff, 0xff]);
+        ppu.evaluate_sprites();
+        assert!(!overflow(&ppu));
+
+        // Off the screen, but its tile number is in range
+        ppu.oam_data[36..40].copy_from_slice(&[0xff, 28, 0xff, 0xff]);
+        ppu.evaluate_sprites();
+        assert!(overflow(&ppu));
+        assert_eq!(ppu.sprites.len(), 8);
+    }
+
+    #[test]
+    fn test_8x16_sprites() {
+        let mut ppu = ines_ppu(1, 0, 0);
+        // First row of tiles $02 and $03 at $1000, tile $03 at $0000 as a decoy
+        for (addr, data) in [(0x1020, 0x0f), (0x1030, 0xf0), (0x0030, 0xff)] {
+            ppu.cartridge.borrow_mut().write_chr(addr, data);
+        }
+        ppu.write_ctrl(0b0010_0000);
+        ppu.oam_data.fill(0xff);
+        ppu.oam_data[0..4].copy_from_slice(&[30, 0x03, 0, 0]);
+
+        // 16 lines tall, with the bottom half from the next tile
+        ppu.scanlines = 45;
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.sprites.len(), 1);
+        ppu.scanlines = 46;
+        ppu.evaluate_sprites();
+        assert!(ppu.sprites.is_empty());
+
+        let top = SpriteUnit { x: 0, tile: 0x03, attr: 0, row: 0, tall: true, lo: 0, hi: 0, sprite_0: true };
+        let bottom = SpriteUnit { row: 8, ..top };
+        assert_eq!(ppu.fetch_sprite_plane(&top, 0), 0x0f);
+        assert_eq!(ppu.fetch_sprite_plane(&bottom, 0), 0xf0);
+
+        // Flipping swaps the halves
+        let top = SpriteUnit { attr: 0b1000_0000, row: 15, ..top };
+        let bottom = SpriteUnit { row: 7, ..top };
+        assert_eq!(ppu.fetch_sprite_plane(&top, 0), 0x0f);
+        assert_eq!(ppu.fetch_sprite_plane(&bottom, 0), 0xf0);
+
+        // 8x8 sprites go back to the table in PPUCTRL
+        let small = SpriteUnit { attr: 0, row: 0, tall: false, ..top };
+        ppu.write_ctrl(0b0000_1000);
+        assert_eq!(ppu.fetch_sprite_plane(&small, 0), 0xf0);
+        ppu.write_ctrl(0);
+        assert_eq!(ppu.fetch_sprite_plane(&small, 0), 0xff);
+    }
+
+    #[test]
+    fn test_sprite_size_changed_after_evaluation() {
+        let mut ppu = ines_ppu(1, 0, 0);
+        ppu.cartridge.borrow_mut().write_chr(0x1020, 0x0f);
+        ppu.write_ctrl(0b0010_0000);
+        ppu.oam_data.fill(0xff);
+        ppu.oam_data[0..4].copy_from_slice(&[30, 0x03, 0b1000_0000, 0]);
+
+        // Row 15 of a flipped 8x16 sprite is the first row of its top tile
+        ppu.scanlines = 45;
+        ppu.evaluate_sprites();
+        assert_eq!(ppu.sprites[0].row, 15);
+
+        // Switching to 8x8 sprites in hblank only applies from the next evaluation
+        ppu.write_ctrl(0);
+        assert_eq!(ppu.fetch_sprite_plane(&ppu.sprites[0], 0), 0x0f);
+        ppu.evaluate_sprites();
+        assert!(ppu.sprites.is_empty());
+    }
+
+    // Solid tile 1 down one column of the background and as sprite 0. Returns the line
+    // and dot where the hit flag goes up in a full frame.
+    fn sprite_0_hit_position(column: u8, sprite_x: u8, attr: u8, mask: u8) -> Option<(usize, usize)> {
+        let mut ppu = ines_ppu(1, 0, 0);
+        ppu.write_addr(0x00);
+        ppu.write_addr(0x10);
+        for _ in 0..8 {
+            ppu.write_data(0xff);
+        }
+        ppu.write_ctrl(0b100);
+        ppu.write_addr(0x20);
+        ppu.write_addr(column);
+        for _ in 0..30 {
+            ppu.write_data(1);
+        }
+        ppu.write_ctrl(0);
+        ppu.write_scrl(0);
+        ppu.write_scrl(0);
+        ppu.oam_data.fill(0xff);
+        ppu.oam_data[0..4].copy_from_slice(&[49, 1, attr, sprite_x]);
+        ppu.write_mask(mask);
+
+        while ppu.tick_single() != TickResult::ScanlineReset {}
+        loop {
+            let position = (ppu.scanlines, ppu.cycles);
+            if ppu.tick_single() == TickResult::ShouldUpdateTexture {
+                return None;
+            }
+            if ppu.reg.stat.contains(StatusRegister::SPRITE_0_HIT) {
+                return Some(position);
+            }
+        }
+    }
+
+    // https://www.nesdev.org/wiki/PPU_OAM#Sprite_zero_hits
+    #[test]
+    fn test_sprite_0_hit() {
+        const SHOW_ALL: u8 = 0b0001_1110;
+        // The first opaque pixel of the pair, x=4 at dot 5
+        assert_eq!(sprite_0_hit_position(0, 4, 0, SHOW_ALL), Some((50, 5)));
+        assert_eq!(sprite_0_hit_position(1, 4, 0, SHOW_ALL), Some((50, 9)));
+        // Priority doesn't matter
+        assert_eq!(sprite_0_hit_position(0, 4, 0b0010_0000, SHOW_ALL), Some((50, 5)));
+
+        // Clipping of either layer in the left 8 pixels
+        assert_eq!(sprite_0_hit_position(0, 4, 0, 0b0001_1100), None);
+        assert_eq!(sprite_0_hit_position(0, 4, 0, 0b0001_1010), None);
+        assert_eq!(sprite_0_hit_position(1, 4, 0, 0b0001_1000), Some((50, 9)));
+
+        // Never at x=255
+        assert_eq!(sprite_0_hit_position(31, 255, 0, SHOW_ALL), None);
+        assert_eq!(sprite_0_hit_position(31, 254, 0, SHOW_ALL), Some((50, 255)));
+
+        // Either layer disabled
+        assert_eq!(sprite_0_hit_position(0, 4, 0, 0b0001_0110), None);
+        assert_eq!(sprite_0_hit_position(0, 4, 0, 0b0000_1110), None);
+    }
+
     #[test]
     fn test_oam_read_write() {
         let mut ppu = Ppu::new();
@@ -1,61 +1,6 @@
 
 use bitflags::bitflags;
 
-pub struct AddressRegister {
-    buf_hi: u8,
-    buf_lo: u8,
-    buf_sel: bool, // true: hi, false: lo
-}
-
-impl AddressRegister {
-    pub fn new() -> Self {
-        AddressRegister {
-            buf_hi: 0,
-            buf_lo: 0,
-            buf_sel: true,
-        }
-    }
-
-    pub fn get(&self) -> u16 {
-        ((self.buf_hi as u16) << 8) | (self.buf_lo as u16)
-    }
-
-    fn set(&mut self, data: u16) {
-        self.buf_hi = (data >> 8) as u8;
-        self.buf_lo = (data & 0xff) as u8;
-    }
-
-    pub fn update(&mut self, data: u8) {
-        if self.buf_sel {
-            self.buf_hi = data;
-        } else {
-            self.buf_lo = data;
-        }
-
-        if self.get() > 0x3fff {
-            self.set(self.get() & 0b0011_1111_1111_1111);
-        }
-        //println!("ppu addr reg: {:04X}", self.get());
-        self.buf_sel = !self.buf_sel;
-    }
-
-    pub fn increment(&mut self, amount: u8) {
-        let lo = self.buf_lo;
-        self.buf_lo = self.buf_lo.wrapping_add(amount);
-        if lo > self.buf_lo {
-            self.buf_hi = self.buf_hi.wrapping_add(1);
-        }
-        if self.get() > 0x3fff {
-            self.set(self.get() & 0b0011_1111_1111_1111);
-        }
-        //println!("ppu addr inc: {:04X}", self.get());
-    }
-
-    pub fn reset_latch(&mut self) {
-        self.buf_sel = true;
-    }
-}
-
 bitflags! {
     // 7  bit  0
     // ---- ----
@@ -155,37 +100,3 @@ impl ControlRegister {
         self.contains(ControlRegister::GENERATE_NMI)
     }
 }
-
-pub struct ScrollRegister {
-    buf_hi: u8,
-    buf_lo: u8,
-    buf_sel: bool, // true: hi, false: lo
-}
-
-impl ScrollRegister {
-    pub fn new() -> Self {
-        ScrollRegister {
-            buf_hi: 0,
-            buf_lo: 0,
-            buf_sel: true,
-        }
-    }
-
-    pub fn get(&self) -> (u8, u8) {
-        (self.buf_hi, self.buf_lo)
-    }
-
-    pub fn update(&mut self, data: u8) {
-        if self.buf_sel {
-            self.buf_hi = data;
-        } else {
-            self.buf_lo = data;
-        }
-
-        self.buf_sel = !self.buf_sel;
-    }
-
-    pub fn reset_latch(&mut self) {
-        self.buf_sel = true;
-    }
-}
\ No newline at end of file
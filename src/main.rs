@@ -13,7 +13,7 @@ fn main() {
         }    
     } else {
         let filename = std::path::Path::new(&args[0]).file_name().unwrap().to_str().unwrap();
-        println!("{} *.nes", filename);
+        println!("{} *.nes|*.zip|*.gz [--rom name.nes] [--patch *.ips|*.ups|*.bps] [--db nes20db.xml] [--region ntsc|pal|dendy]", filename);
         println!("{} chr *.nes", filename);
         println!("{} nestest nestest.nes", filename);
     }
@@ -1,10 +1,11 @@
-use core::panic;
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 use std::thread::sleep;
 use std::time::{Duration, Instant};
 
 use apu::init_apu;
-use cartridge::Cartridge;
+use bus::Bus;
+use cartridge::{Cartridge, GameDatabase, Region};
 use cpu::Cpu;
 use joypad::JoypadButton;
 use ppu::{HEIGHT, WIDTH};
@@ -26,6 +27,9 @@ enum Action {
     Joypad(joypad::JoypadButton),
     ToggleTrace,
     ToggleFrameWait,
+    Reset,
+    ToggleSpriteLimit,
+    Quit,
     None,
 }
 
@@ -40,18 +44,20 @@ lazy_static! {
         (Keycode::A, Action::Joypad(JoypadButton::BUTTON_A)),
         (Keycode::S, Action::Joypad(JoypadButton::BUTTON_B)),
         (Keycode::T, Action::ToggleTrace),
-        (Keycode::F, Action::ToggleFrameWait)
+        (Keycode::F, Action::ToggleFrameWait),
+        (Keycode::R, Action::Reset),
+        (Keycode::L, Action::ToggleSpriteLimit)
     ]);
 }
 
-fn handle_user_input(cpu: &mut Cpu, event_pump: &mut EventPump) -> Action {
+fn handle_user_input(cpu: &mut Cpu<Bus>, event_pump: &mut EventPump) -> Action {
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
             | Event::KeyDown {
                 keycode: Some(Keycode::Escape),
                 ..
-            } => std::process::exit(0),
+            } => return Action::Quit,
             Event::KeyDown { keycode, .. } => {
                 let keycode = keycode.unwrap_or(Keycode::Ampersand);
                 if let Some(action) = KEY_MAP.get(&keycode) {
@@ -82,6 +88,32 @@ fn handle_user_input(cpu: &mut Cpu, event_pump: &mut EventPump) -> Action {
     return Action::None;
 }
 
+// Writes battery-backed PRG-RAM to the .sav file if it has been modified
+fn flush_battery_ram(cpu: &Cpu<Bus>, sav_path: &Path) {
+    let mut cartridge = cpu.bus.cartridge.borrow_mut();
+    if cartridge.is_battery_ram_dirty() {
+        if let Err(e) = cartridge.save_battery_ram(sav_path) {
+            eprintln!("Could not save {}: {}", sav_path.display(), e);
+        }
+    }
+}
+
+// Value following a flag, e.g. --db nes20db.xml
+fn option_value<'a>(args: &'a [String], name: &str) -> Option<&'a String> {
+    args.iter().position(|arg| arg == name).and_then(|i| args.get(i + 1))
+}
+
+// --patch, or a same-named .ips/.ups/.bps next to the ROM
+fn find_patch(args: &[String], filename: &str) -> Option<PathBuf> {
+    if let Some(path) = option_value(args, "--patch") {
+        return Some(PathBuf::from(path));
+    }
+    ["ips", "ups", "bps"]
+        .iter()
+        .map(|ext| Path::new(filename).with_extension(ext))
+        .find(|path| path.exists())
+}
+
 pub fn nes_emulator(args: Vec<String>) {
     const SCALE: usize = 3;
 
@@ -110,25 +142,71 @@ pub fn nes_emulator(args: Vec<String>) {
 
     // Read cartridge
     let filename = &args[1];
-    let raw = std::fs::read(filename).expect("Could not read the file");
-    let cartridge = Cartridge::load(&raw).expect("Invalid cartridge data");
-    let fps = match cartridge.video_signal {
-        cartridge::VideoSignal::NTSC => 59.94,
-        _ => panic!(),
+    let file = std::fs::read(filename).expect("Could not read the file");
+    // --rom picks the ROM in an archive holding several
+    let mut raw = match cartridge::extract_rom(&file, option_value(&args, "--rom").map(|s| s.as_str())) {
+        Ok(raw) => raw.into_owned(),
+        Err(e) => {
+            eprintln!("Could not open {}: {}", filename, e);
+            std::process::exit(1);
+        }
     };
-    let msec_per_frame = 1000.0 / fps;
+    if let Some(patch_path) = find_patch(&args, filename) {
+        let patch = std::fs::read(&patch_path).expect("Could not read the patch");
+        raw = match cartridge::apply_patch(&raw, &patch) {
+            Ok(patched) => patched,
+            Err(e) => {
+                eprintln!("Could not apply {}: {}", patch_path.display(), e);
+                std::process::exit(1);
+            }
+        };
+        println!("Applied {}", patch_path.display());
+    }
+    let db = match option_value(&args, "--db") {
+        Some(path) => GameDatabase::load(Path::new(path)).expect("Could not read the database"),
+        None => GameDatabase::new(),
+    };
+    let mut cartridge = match Cartridge::load_with_db(&raw, &db, |line| eprintln!("{}", line)) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("Invalid cartridge data: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    // Restore battery-backed PRG-RAM from the .sav file next to the ROM
+    let sav_path = Path::new(filename).with_extension("sav");
+    if cartridge.header.battery {
+        match cartridge.load_battery_ram(&sav_path) {
+            Ok(_) => println!("Loaded {}", sav_path.display()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => eprintln!("Could not load {}: {}", sav_path.display(), e),
+        }
+    }
 
     // Associate cartridge to bus
-    let mut cpu = Cpu::new();
+    let mut cpu: Cpu<Bus> = Cpu::new();
     cpu.bus.load_cartridge(cartridge);
-    let vector = cpu.bus.read16(0xfffc);
-    cpu.set_pc(vector);
+    if let Some(region) = option_value(&args, "--region") {
+        cpu.set_region(match region.as_str() {
+            "ntsc" => Region::NTSC,
+            "pal" => Region::PAL,
+            "dendy" => Region::Dendy,
+            _ => {
+                eprintln!("Unknown region {}, expected ntsc|pal|dendy", region);
+                std::process::exit(1);
+            }
+        });
+    }
+    println!("Region: {:?}", cpu.bus.region());
+    let fps = cpu.bus.region().frame_rate();
 
     // Associate apu to bus
     // Init apu
     let apu = init_apu(&sdl_context);
     apu.audio_device.as_ref().unwrap().resume();
     cpu.bus.associate_apu(apu);
+    cpu.power_cycle();
 
     // For trace
     let mut prev_line = String::new();
@@ -156,6 +234,18 @@ pub fn nes_emulator(args: Vec<String>) {
                     settings.wait = !settings.wait;
                     println!("Wait: {}", settings.wait);
                 }
+                Action::Reset => {
+                    cpu.reset();
+                }
+                Action::ToggleSpriteLimit => {
+                    let sprite_limit = !cpu.bus.ppu.sprite_limit();
+                    cpu.bus.ppu.set_sprite_limit(sprite_limit);
+                    println!("Sprite limit: {}", sprite_limit);
+                }
+                Action::Quit => {
+                    flush_battery_ram(cpu, &sav_path);
+                    std::process::exit(0);
+                }
                 _ => {}
             }
 
@@ -180,9 +270,14 @@ pub fn nes_emulator(args: Vec<String>) {
             canvas.present();
             frame_count += 1;
 
+            // Save periodically so a crash loses at most a few seconds of progress
+            if frame_count.is_multiple_of(300) {
+                flush_battery_ram(cpu, &sav_path);
+            }
+
             let current_time = Instant::now();
             let elapsed_time_real = current_time - start_time;
-            let elapsed_time_nes = Duration::from_millis(frame_count * msec_per_frame as u64);
+            let elapsed_time_nes = Duration::from_secs_f64(frame_count as f64 / fps);
             let should_wait = elapsed_time_nes > elapsed_time_real;
             if should_wait {
                 if settings.wait {
@@ -204,4 +299,10 @@ pub fn nes_emulator(args: Vec<String>) {
             }
         },
     );
+
+    if let Some(jam) = cpu.jammed() {
+        eprintln!("{}: {}", jam, cpu.trace());
+        flush_battery_ram(&cpu, &sav_path);
+        std::process::exit(1);
+    }
 }
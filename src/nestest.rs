@@ -1,12 +1,15 @@
+use bus::Bus;
 use cartridge::Cartridge;
 use cpu::Cpu;
 
 pub fn nestest(args: Vec<String>) {
     let raw = std::fs::read(&args[2]).expect("Could not read the file");
     let cartridge = Cartridge::load(&raw).expect("Invalid cartridge data");
-    let mut cpu = Cpu::new();
+    let mut cpu: Cpu<Bus> = Cpu::new();
     cpu.bus.load_cartridge(cartridge);
     cpu.set_pc(0xc000);
+    // Stop at the end of the test instead of jumping through the BRK vector
+    cpu.set_halt_on_brk(true);
 
     cpu.run_with_callback(&mut 0, |cpu, _| {
         let line = cpu.trace();